@@ -269,6 +269,26 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     });
 
+    let s = raft_large.clone();
+    c.bench_function("raft large x1 intern_many", move |b| {
+        b.iter(|| {
+            unsafe { ustr::_clear_cache() };
+            black_box(intern_many(
+                s.iter().cycle().take(100_000).map(|s| s.as_str()),
+            ));
+        });
+    });
+
+    let s = raft_large.clone();
+    c.bench_function("raft large x1 UstrPath", move |b| {
+        b.iter(|| {
+            unsafe { ustr::_clear_cache() };
+            for s in s.iter().cycle().take(100_000) {
+                black_box(UstrPath::new(s));
+            }
+        });
+    });
+
     let num_threads = 6;
     let s = raft_large.clone();
     c.bench_function("raft large x6", move |b| {
@@ -305,6 +325,46 @@ fn criterion_benchmark(c: &mut Criterion) {
         })
         .unwrap();
     });
+
+    let s = raft_large.clone();
+    c.bench_function("raft large x6 intern_many", move |b| {
+        let (tx1, rx1) = bounded(0);
+        let (tx2, rx2) = bounded(0);
+        let s = Arc::clone(&s);
+        scope(|scope| {
+            for tt in 0..num_threads {
+                let t = tt;
+                let rx1 = rx1.clone();
+                let tx2 = tx2.clone();
+                let s = Arc::clone(&s);
+                scope.spawn(move |_| {
+                    while rx1.recv().is_ok() {
+                        black_box(intern_many(
+                            s.iter()
+                                .cycle()
+                                .skip(t * 17)
+                                .take(num)
+                                .map(|s| s.as_str()),
+                        ));
+                        tx2.send(()).unwrap();
+                    }
+                });
+            }
+
+            b.iter(|| {
+                unsafe { ustr::_clear_cache() };
+                for _ in 0..num_threads {
+                    tx1.send(()).unwrap();
+                }
+
+                for _ in 0..num_threads {
+                    rx2.recv().unwrap();
+                }
+            });
+            drop(tx1);
+        })
+        .unwrap();
+    });
 }
 
 criterion_group!(
@@ -29,6 +29,23 @@ size_t ustr_len(ustr_t u);
 */
 uint64_t ustr_hash(ustr_t u);
 
+typedef struct {
+    const char* ptr;
+} ubytes_t;
+
+/*
+    Create a new ubytes_t from the `len` bytes at `bytes`, which may contain
+    nulls and don't have to be valid UTF-8.
+    It is assumed that `bytes` points to at least `len` readable bytes. It may
+    be null if `len` is 0.
+*/
+ubytes_t ubytes(const char* bytes, size_t len);
+
+/*
+    Returns the length of the given ubytes_t in bytes.
+*/
+size_t ubytes_len(ubytes_t b);
+
 #ifdef __cplusplus
 }
 #endif
@@ -38,4 +38,32 @@ public:
     const char* c_str() const { return _u.ptr; }
 };
 
+/// A class representing an interned byte string.
+class UBytes {
+    ubytes_t _b;
+
+public:
+    /// Creates the empty byte string
+    UBytes() { _b = ubytes(nullptr, 0); }
+
+    /// Create a new UBytes from `len` bytes, which may contain nulls
+    /// It is assumed that `ptr` points to at least `len` readable bytes.
+    UBytes(const char* ptr, size_t len) { _b = ubytes(ptr, len); }
+
+    /// Create a new UBytes from a std::string, including any nulls in it
+    UBytes(const std::string& s) { _b = ubytes(s.data(), s.size()); }
+
+    /// Returns true if the byte string is empty
+    bool is_empty() const { return len() == 0; }
+
+    /// Returns the length of the byte string
+    size_t len() const { return ubytes_len(_b); }
+
+    /// Easy conversion to the underlying C struct
+    operator ubytes_t() const { return _b; }
+
+    /// Get the interned bytes, which are followed by a null
+    const char* data() const { return _b.ptr; }
+};
+
 #endif
\ No newline at end of file
@@ -1,8 +1,8 @@
 use std::alloc::{GlobalAlloc, Layout, System};
 
 // The world's dumbest allocator. Just keep bumping a pointer until we run out
-// of memory, in which case we abort. StringCache is responsible for creating
-// a new allocator when that's about to happen.
+// of memory, in which case we refuse to allocate. StringCache is responsible
+// for creating a new allocator before that happens.
 // This is now bumping downward rather than up, which simplifies the allocate()
 // method and gives a small (5-7%) performance improvement in multithreaded
 // benchmarks
@@ -16,19 +16,33 @@ pub(crate) struct LeakyBumpAlloc {
 
 impl LeakyBumpAlloc {
     pub fn new(capacity: usize, alignment: usize) -> LeakyBumpAlloc {
-        let layout = Layout::from_size_align(capacity, alignment).unwrap();
+        match LeakyBumpAlloc::try_new(capacity, alignment) {
+            Some(alloc) => alloc,
+            None => panic!("oom"),
+        }
+    }
+
+    // Create a new allocator, or return `None` if the memory for it could not
+    // be allocated. `capacity` should be a multiple of `alignment` so that
+    // every allocation stays aligned.
+    pub fn try_new(
+        capacity: usize,
+        alignment: usize,
+    ) -> Option<LeakyBumpAlloc> {
+        debug_assert!(capacity > 0 && capacity & (alignment - 1) == 0);
+        let layout = Layout::from_size_align(capacity, alignment).ok()?;
         let start = unsafe { System.alloc(layout) };
         if start.is_null() {
-            panic!("oom");
+            return None;
         }
         let end = unsafe { start.add(layout.size()) };
         let ptr = end;
-        LeakyBumpAlloc {
+        Some(LeakyBumpAlloc {
             layout,
             start,
             end,
             ptr,
-        }
+        })
     }
 
     #[doc(hidden)]
@@ -37,28 +51,31 @@ impl LeakyBumpAlloc {
         System.dealloc(self.start, self.layout);
     }
 
-    // Allocates a new chunk. Aborts if out of memory.
-    pub unsafe fn allocate(&mut self, num_bytes: usize) -> *mut u8 {
+    // Allocates a new chunk. Returns `None` if there isn't enough capacity
+    // left, in which case the allocator is unchanged.
+    pub unsafe fn allocate(&mut self, num_bytes: usize) -> Option<*mut u8> {
         // Our new ptr will be offset down the heap by num_bytes bytes.
         let ptr = self.ptr as usize;
-        let new_ptr = ptr.checked_sub(num_bytes).expect("ptr sub overflowed");
+        let new_ptr = ptr.checked_sub(num_bytes)?;
         // Round down to alignment.
         let new_ptr = new_ptr & !(self.layout.align() - 1);
         // Check we have enough capacity.
         let start = self.start as usize;
         if new_ptr < start {
-            eprintln!(
-                "Allocator asked to bump to {} bytes with a capacity of {}",
-                self.end as usize - new_ptr,
-                self.capacity()
-            );
-            // We have to abort here rather than panic or the mutex may
-            // deadlock.
-            std::process::abort();
+            return None;
         }
 
         self.ptr = self.ptr.sub(ptr - new_ptr);
-        self.ptr
+        Some(self.ptr)
+    }
+
+    // Give back the chunk that was just allocated. `num_bytes` must be what
+    // was passed to `allocate()`, and a multiple of the alignment.
+    #[cfg(feature = "ids")]
+    pub unsafe fn unallocate(&mut self, ptr: *mut u8, num_bytes: usize) {
+        debug_assert!(ptr == self.ptr);
+        debug_assert!(num_bytes & (self.layout.align() - 1) == 0);
+        self.ptr = ptr.add(num_bytes);
     }
 
     pub fn allocated(&self) -> usize {
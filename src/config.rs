@@ -0,0 +1,337 @@
+use super::hash::HashAlgorithm;
+use super::stringcache::{
+    round_up_to, StringCacheEntry, INITIAL_ALLOC, INITIAL_CAPACITY, NUM_BINS,
+};
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Geometry of a string cache.
+///
+/// Use [`configure()`] to get one of these with the default settings, adjust
+/// it with the builder methods and then call [`CacheConfig::init`] to install
+/// it as the configuration for the global cache. This has to happen before
+/// the first `Ustr` is created, since the cache is set up lazily on first use
+/// and cannot be resized after that.
+///
+/// # Examples
+///
+/// ```
+/// // A small cache for a short-lived tool.
+/// ustr::configure()
+///     .initial_capacity(1 << 12)
+///     .initial_alloc(64 << 10)
+///     .num_bins(8)
+///     .init()
+///     .unwrap();
+///
+/// let u = ustr::ustr("configured");
+/// assert_eq!(u, "configured");
+///
+/// // Too late to change it now.
+/// assert_eq!(
+///     ustr::configure().num_bins(256).init(),
+///     Err(ustr::ConfigError::AlreadyInitialized)
+/// );
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheConfig {
+    pub(crate) initial_capacity: usize,
+    pub(crate) initial_alloc: usize,
+    pub(crate) num_bins: usize,
+    pub(crate) growth_factor: f64,
+    pub(crate) load_factor: f64,
+    pub(crate) memory_budget: Option<usize>,
+    pub(crate) max_entries: Option<usize>,
+    pub(crate) max_string_len: Option<usize>,
+    pub(crate) hash_algorithm: HashAlgorithm,
+}
+
+impl CacheConfig {
+    /// Total number of slots in the hash table across all bins. This is
+    /// divided evenly between the bins and rounded up to a power of two in
+    /// each. Defaults to 2^20.
+    pub fn initial_capacity(mut self, slots: usize) -> Self {
+        self.initial_capacity = slots;
+        self
+    }
+
+    /// Total size in bytes of the first string storage arena across all
+    /// bins. Defaults to 4MiB.
+    pub fn initial_alloc(mut self, bytes: usize) -> Self {
+        self.initial_alloc = bytes;
+        self
+    }
+
+    /// Number of bins (shards), each with its own lock. Must be a power of
+    /// two. Defaults to 64.
+    pub fn num_bins(mut self, bins: usize) -> Self {
+        self.num_bins = bins;
+        self
+    }
+
+    /// Factor by which each new storage arena is bigger than the last one
+    /// when a bin runs out of space. Must be greater than 1. Defaults to 2.
+    pub fn growth_factor(mut self, factor: f64) -> Self {
+        self.growth_factor = factor;
+        self
+    }
+
+    /// Fraction of a bin's table that may be occupied before the table is
+    /// doubled in size. Must be strictly between 0 and 1. Defaults to 0.5.
+    pub fn load_factor(mut self, factor: f64) -> Self {
+        self.load_factor = factor;
+        self
+    }
+
+    /// Maximum number of bytes of string storage the cache may reserve,
+    /// counting the initial storage for every bin. Once this is reached,
+    /// [`try_ustr()`](crate::try_ustr) returns
+    /// [`InternError::BudgetExceeded`](crate::InternError) for new strings,
+    /// and [`ustr()`](crate::ustr) panics. The global caches for byte
+    /// strings, paths and lists share this budget. Unlimited by default.
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Maximum number of unique strings the cache may hold. Interning a new
+    /// string beyond this fails with
+    /// [`InternError::TooManyEntries`](crate::InternError). Byte strings,
+    /// paths and lists in the other global caches count towards this too.
+    /// Unlimited by default.
+    pub fn max_entries(mut self, entries: usize) -> Self {
+        self.max_entries = Some(entries);
+        self
+    }
+
+    /// Maximum length in bytes of a single string. Interning a new string
+    /// longer than this fails with
+    /// [`InternError::StringTooLong`](crate::InternError). This applies to
+    /// strings and byte strings, but not to paths or lists. Unlimited by
+    /// default.
+    pub fn max_string_len(mut self, bytes: usize) -> Self {
+        self.max_string_len = Some(bytes);
+        self
+    }
+
+    /// The hash function used for the strings. Defaults to
+    /// [`HashAlgorithm::AHash`], whichever hash features are enabled.
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Check that the configuration makes sense.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.num_bins == 0 || !self.num_bins.is_power_of_two() {
+            return Err(ConfigError::InvalidNumBins(self.num_bins));
+        }
+        if self.initial_capacity == 0 {
+            return Err(ConfigError::InvalidCapacity);
+        }
+        if self.initial_alloc == 0 {
+            return Err(ConfigError::InvalidAlloc);
+        }
+        // Written this way round so that NaN is rejected too.
+        if !(self.growth_factor > 1.0 && self.growth_factor.is_finite()) {
+            return Err(ConfigError::InvalidGrowthFactor(self.growth_factor));
+        }
+        if !(self.load_factor > 0.0 && self.load_factor < 1.0) {
+            return Err(ConfigError::InvalidLoadFactor(self.load_factor));
+        }
+        if let Some(budget) = self.memory_budget {
+            let initial = self.bin_alloc().saturating_mul(self.num_bins);
+            if budget < initial {
+                return Err(ConfigError::BudgetTooSmall { budget, initial });
+            }
+        }
+        Ok(())
+    }
+
+    /// Install this configuration for the global cache.
+    ///
+    /// Returns [`ConfigError::AlreadyInitialized`] if the global cache has
+    /// already been created, i.e. if any `Ustr` has been created or any
+    /// function querying the cache has been called. Calling this more than
+    /// once before that replaces the previous configuration.
+    pub fn init(self) -> Result<(), ConfigError> {
+        self.validate()?;
+        let mut pending = PENDING_CONFIG.lock();
+        if CACHE_INITIALIZED.load(Ordering::SeqCst) {
+            return Err(ConfigError::AlreadyInitialized);
+        }
+        *pending = Some(self);
+        Ok(())
+    }
+
+    // Number of table slots in each bin.
+    pub(crate) fn bin_capacity(&self) -> usize {
+        (self.initial_capacity / self.num_bins)
+            .max(2)
+            .next_power_of_two()
+    }
+
+    // Size in bytes of the first arena in each bin.
+    pub(crate) fn bin_alloc(&self) -> usize {
+        let bytes = (self.initial_alloc / self.num_bins)
+            .max(std::mem::size_of::<StringCacheEntry>());
+        round_up_to(bytes, std::mem::align_of::<StringCacheEntry>())
+            .expect("initial allocation size overflowed")
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            initial_capacity: INITIAL_CAPACITY,
+            initial_alloc: INITIAL_ALLOC,
+            num_bins: NUM_BINS,
+            growth_factor: 2.0,
+            load_factor: 0.5,
+            memory_budget: None,
+            max_entries: None,
+            max_string_len: None,
+            hash_algorithm: HashAlgorithm::AHash,
+        }
+    }
+}
+
+/// Start configuring the global cache.
+///
+/// See [`CacheConfig`] for details.
+pub fn configure() -> CacheConfig {
+    CacheConfig::default()
+}
+
+/// Error returned when a [`CacheConfig`] cannot be applied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// The global cache was already created so can no longer be configured.
+    AlreadyInitialized,
+    /// The number of bins must be a non-zero power of two.
+    InvalidNumBins(usize),
+    /// The initial capacity must be non-zero.
+    InvalidCapacity,
+    /// The initial allocation size must be non-zero.
+    InvalidAlloc,
+    /// The growth factor must be greater than 1.
+    InvalidGrowthFactor(f64),
+    /// The load factor must be strictly between 0 and 1.
+    InvalidLoadFactor(f64),
+    /// The memory budget is smaller than the initial storage for all bins.
+    BudgetTooSmall {
+        /// The requested budget in bytes.
+        budget: usize,
+        /// The initial storage for all bins in bytes.
+        initial: usize,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::AlreadyInitialized => {
+                write!(f, "the string cache has already been initialized")
+            }
+            ConfigError::InvalidNumBins(n) => {
+                write!(f, "number of bins must be a power of two, got {}", n)
+            }
+            ConfigError::InvalidCapacity => {
+                write!(f, "initial capacity must be non-zero")
+            }
+            ConfigError::InvalidAlloc => {
+                write!(f, "initial allocation size must be non-zero")
+            }
+            ConfigError::InvalidGrowthFactor(x) => {
+                write!(f, "growth factor must be greater than 1, got {}", x)
+            }
+            ConfigError::InvalidLoadFactor(x) => {
+                write!(f, "load factor must be between 0 and 1, got {}", x)
+            }
+            ConfigError::BudgetTooSmall { budget, initial } => write!(
+                f,
+                "memory budget of {} bytes is less than the {} bytes of \
+                 initial storage",
+                budget, initial
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+lazy_static::lazy_static! {
+    // Configuration waiting to be picked up when the global cache is created.
+    // The lock is also held while the cache is being created so that `init()`
+    // can't race with it.
+    pub(crate) static ref PENDING_CONFIG: Mutex<Option<CacheConfig>> =
+        Mutex::new(None);
+}
+
+pub(crate) static CACHE_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Get the configuration for creating one of the global caches. After this,
+// `init()` can no longer change it.
+pub(crate) fn global_config() -> CacheConfig {
+    let pending = PENDING_CONFIG.lock();
+    CACHE_INITIALIZED.store(true, Ordering::SeqCst);
+    pending.clone().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{configure, ConfigError};
+
+    #[test]
+    fn validation() {
+        assert_eq!(
+            configure().num_bins(3).validate(),
+            Err(ConfigError::InvalidNumBins(3))
+        );
+        assert_eq!(
+            configure().num_bins(0).validate(),
+            Err(ConfigError::InvalidNumBins(0))
+        );
+        assert_eq!(
+            configure().initial_capacity(0).validate(),
+            Err(ConfigError::InvalidCapacity)
+        );
+        assert_eq!(
+            configure().growth_factor(1.0).validate(),
+            Err(ConfigError::InvalidGrowthFactor(1.0))
+        );
+        assert_eq!(
+            configure().load_factor(1.0).validate(),
+            Err(ConfigError::InvalidLoadFactor(1.0))
+        );
+        assert!(configure().load_factor(f64::NAN).validate().is_err());
+        assert_eq!(
+            configure().memory_budget(1 << 20).validate(),
+            Err(ConfigError::BudgetTooSmall {
+                budget: 1 << 20,
+                initial: 4 << 20,
+            })
+        );
+        assert_eq!(configure().validate(), Ok(()));
+    }
+
+    #[test]
+    fn too_late() {
+        let _t = crate::TEST_LOCK.lock();
+        let _ = crate::ustr("too late");
+        assert_eq!(configure().init(), Err(ConfigError::AlreadyInitialized));
+    }
+
+    #[test]
+    fn bin_geometry() {
+        let config = configure().num_bins(4).initial_capacity(20);
+        assert_eq!(config.bin_capacity(), 8);
+        assert_eq!(config.bin_alloc(), (4 << 20) / 4);
+
+        let config = configure().num_bins(64).initial_capacity(1);
+        assert_eq!(config.bin_capacity(), 2);
+    }
+}
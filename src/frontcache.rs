@@ -0,0 +1,167 @@
+// A small direct-mapped cache of recently interned strings for each thread,
+// checked before going to the global cache. Interning the same few strings
+// over and over then only costs a hash and a comparison with the cached
+// string.
+//
+// Each slot remembers the hash of a string and a pointer to its chars in the
+// global cache. Slots also record the generation of the global cache they
+// were filled in, which `_clear_cache()` bumps, so that a thread never hands
+// out a pointer to a string that was cleared.
+use super::{InternError, StringCacheEntry};
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// Number of slots, which must be a power of two.
+const NUM_SLOTS: usize = 256;
+
+// The generation of the global cache. Slots with generation 0 are empty.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Copy)]
+struct Slot {
+    hash: u64,
+    char_ptr: *const u8,
+    generation: u64,
+}
+
+// Only used to initialize the slots.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Cell<Slot> = Cell::new(Slot {
+    hash: 0,
+    char_ptr: std::ptr::null(),
+    generation: 0,
+});
+
+struct FrontCache {
+    slots: [Cell<Slot>; NUM_SLOTS],
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+thread_local! {
+    static FRONT_CACHE: FrontCache = const {
+        FrontCache {
+            slots: [EMPTY_SLOT; NUM_SLOTS],
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    };
+}
+
+impl FrontCache {
+    fn get(
+        &self,
+        string: &str,
+        hash: u64,
+        generation: u64,
+    ) -> Option<*const u8> {
+        let slot = self.slots[hash as usize & (NUM_SLOTS - 1)].get();
+        if slot.generation == generation && slot.hash == hash {
+            // This is safe as the pointer came from the global cache in this
+            // generation, so it still points to a valid entry.
+            let sce = unsafe { StringCacheEntry::from_char_ptr(slot.char_ptr) };
+            // Different strings can have the same hash.
+            if sce.as_str() == string {
+                self.hits.set(self.hits.get() + 1);
+                return Some(slot.char_ptr);
+            }
+        }
+        self.misses.set(self.misses.get() + 1);
+        None
+    }
+
+    fn put(&self, hash: u64, char_ptr: *const u8, generation: u64) {
+        self.slots[hash as usize & (NUM_SLOTS - 1)].set(Slot {
+            hash,
+            char_ptr,
+            generation,
+        });
+    }
+}
+
+// Get the string from this thread's cache, or call `insert` to intern it in
+// the global cache and remember it.
+#[inline]
+pub(crate) fn get_or_insert<F>(
+    string: &str,
+    hash: u64,
+    insert: F,
+) -> Result<*const u8, InternError>
+where
+    F: FnOnce() -> Result<*const u8, InternError>,
+{
+    let generation = GENERATION.load(Ordering::Acquire);
+    // The thread local might already have been destroyed if we're called
+    // from another thread local's destructor, in which case we just go
+    // straight to the global cache.
+    let hit = FRONT_CACHE
+        .try_with(|fc| fc.get(string, hash, generation))
+        .ok()
+        .flatten();
+    if let Some(char_ptr) = hit {
+        return Ok(char_ptr);
+    }
+    let char_ptr = insert()?;
+    let _ = FRONT_CACHE.try_with(|fc| fc.put(hash, char_ptr, generation));
+    Ok(char_ptr)
+}
+
+// Forget everything in every thread's cache, used when clearing the global
+// cache.
+pub(crate) fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Returns the number of times interning a string on this thread found it in
+/// the thread's front cache.
+///
+/// Only available with the `"thread-cache"` feature.
+pub fn thread_cache_hits() -> u64 {
+    FRONT_CACHE.with(|fc| fc.hits.get())
+}
+
+/// Returns the number of times interning a string on this thread didn't find
+/// it in the thread's front cache, and had to look in the global cache.
+///
+/// Only available with the `"thread-cache"` feature.
+pub fn thread_cache_misses() -> u64 {
+    FRONT_CACHE.with(|fc| fc.misses.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{thread_cache_hits, thread_cache_misses};
+    use crate::ustr;
+
+    #[test]
+    fn hits_and_misses() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+        let (hits, misses) = (thread_cache_hits(), thread_cache_misses());
+
+        let keyword = ustr("while");
+        for _ in 0..9 {
+            assert_eq!(ustr("while"), keyword);
+        }
+        assert_eq!(thread_cache_hits() - hits, 9);
+        assert_eq!(thread_cache_misses() - misses, 1);
+
+        // Clearing the global cache empties the front cache too.
+        unsafe { crate::_clear_cache() };
+        let keyword = ustr("while");
+        assert_eq!(keyword.as_str(), "while");
+        assert_eq!(crate::num_entries(), 1);
+        assert_eq!(thread_cache_misses() - misses, 2);
+
+        // Strings that share a slot push each other out, but are always
+        // the right strings.
+        let blns = include_str!("../data/blns.txt");
+        for _ in 0..2 {
+            for s in blns.split_whitespace() {
+                assert_eq!(ustr(s).as_str(), s);
+            }
+        }
+    }
+}
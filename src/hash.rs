@@ -1,8 +1,10 @@
-use super::Ustr;
+#[cfg(feature = "normalize")]
+use super::UstrNfc;
+use super::{Interned, UBytes, UPath, Ustr, UstrCi, UstrList, UstrPath};
 use byteorder::{ByteOrder, NativeEndian};
 use std::{
     collections::{HashMap, HashSet},
-    hash::{BuildHasherDefault, Hasher},
+    hash::{BuildHasher, BuildHasherDefault, Hash, Hasher},
 };
 
 /// A standard `HashMap` using `Ustr` as the key type with a custom `Hasher`
@@ -13,6 +15,55 @@ pub type UstrMap<V> = HashMap<Ustr, V, BuildHasherDefault<IdentityHasher>>;
 /// that just uses the precomputed hash for speed instead of calculating it.
 pub type UstrSet = HashSet<Ustr, BuildHasherDefault<IdentityHasher>>;
 
+/// Like [`UstrMap`], but with `UBytes` keys.
+pub type UBytesMap<V> = HashMap<UBytes, V, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrSet`], but with `UBytes` keys.
+pub type UBytesSet = HashSet<UBytes, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrMap`], but with `UPath` keys.
+pub type UPathMap<V> = HashMap<UPath, V, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrSet`], but with `UPath` keys.
+pub type UPathSet = HashSet<UPath, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrMap`], but with `UstrCi` keys.
+pub type UstrCiMap<V> = HashMap<UstrCi, V, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrSet`], but with `UstrCi` keys.
+pub type UstrCiSet = HashSet<UstrCi, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrMap`], but with `UstrNfc` keys.
+#[cfg(feature = "normalize")]
+pub type UstrNfcMap<V> =
+    HashMap<UstrNfc, V, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrSet`], but with `UstrNfc` keys.
+#[cfg(feature = "normalize")]
+pub type UstrNfcSet = HashSet<UstrNfc, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrMap`], but with `UstrList` keys.
+pub type UstrListMap<V> =
+    HashMap<UstrList, V, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrSet`], but with `UstrList` keys.
+pub type UstrListSet = HashSet<UstrList, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrMap`], but with `UstrPath` keys.
+pub type UstrPathMap<V> =
+    HashMap<UstrPath, V, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrSet`], but with `UstrPath` keys.
+pub type UstrPathSet = HashSet<UstrPath, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrMap`], but with `Interned<T>` keys.
+pub type InternedMap<T, V> =
+    HashMap<Interned<T>, V, BuildHasherDefault<IdentityHasher>>;
+
+/// Like [`UstrSet`], but with `Interned<T>` keys.
+pub type InternedSet<T> =
+    HashSet<Interned<T>, BuildHasherDefault<IdentityHasher>>;
+
 /// The worst hasher in the world -- the identity hasher.
 #[doc(hidden)]
 #[derive(Default)]
@@ -34,6 +85,253 @@ impl Hasher for IdentityHasher {
     }
 }
 
+/// The hash function a cache uses for its strings.
+///
+/// The hash of each string is computed once when it is interned and stored
+/// with it, which is what [`Ustr::precomputed_hash()`] returns. The default
+/// is AHash with fixed keys, which is fast and good enough for strings that
+/// come from your own program. If the cache interns strings from untrusted
+/// sources, an attacker who knows the hash function can pick strings that
+/// all land in the same slot and slow the cache to a crawl, so use one of
+/// the keyed hashes with secret keys, such as [`HashAlgorithm::random()`].
+///
+/// FxHash, XXH3 and SipHash are only available with the `"fxhash"`, `"xxh3"`
+/// and `"siphash"` features respectively. Enabling one of them doesn't change
+/// the default, which you have to do with
+/// [`CacheConfig::hash_algorithm`](crate::CacheConfig::hash_algorithm).
+/// Cargo turns a feature on for every crate in a build if any one of them
+/// asks for it, so if the default followed the features, a dependency could
+/// change the hashes of your strings without you knowing. SipHash also needs
+/// a key, which only you can choose.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{configure, HashAlgorithm, Interner};
+///
+/// let interner =
+///     Interner::with_config(configure().hash_algorithm(HashAlgorithm::random()))
+///         .unwrap();
+/// assert_eq!(interner.intern("hello"), interner.intern("hello"));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HashAlgorithm {
+    /// AHash with its default, fixed keys.
+    #[default]
+    AHash,
+    /// AHash with the given keys.
+    AHashKeyed([u64; 4]),
+    /// A simple hash defined by this crate, see [`stable_hash()`]. Unlike the
+    /// other algorithms, its results are guaranteed never to change, so they
+    /// can be compared between different machines and different versions of
+    /// this crate.
+    Stable,
+    /// FxHash, as used by rustc. Very fast, but easy to find collisions for.
+    #[cfg(feature = "fxhash")]
+    FxHash,
+    /// 64-bit XXH3.
+    #[cfg(feature = "xxh3")]
+    Xxh3,
+    /// SipHash-1-3 with the given key.
+    #[cfg(feature = "siphash")]
+    SipHash13([u64; 2]),
+}
+
+impl HashAlgorithm {
+    /// AHash with keys chosen at random, which are different in every
+    /// process.
+    pub fn random() -> HashAlgorithm {
+        let state = std::collections::hash_map::RandomState::new();
+        let mut keys = [0; 4];
+        for (i, key) in keys.iter_mut().enumerate() {
+            let mut hasher = state.build_hasher();
+            hasher.write_usize(i);
+            *key = hasher.finish();
+        }
+        HashAlgorithm::AHashKeyed(keys)
+    }
+}
+
+/// The hash of `string` used by [`HashAlgorithm::Stable`].
+///
+/// This is 64-bit FNV-1a over the UTF-8 bytes of the string, with the
+/// offset basis `0xcbf29ce484222325` and prime `0x100000001b3`, followed by
+/// the MurmurHash3 64-bit finalizer to mix the bits:
+///
+/// ```text
+/// h ^= h >> 33; h *= 0xff51afd7ed558ccd;
+/// h ^= h >> 33; h *= 0xc4ceb9fe1a85ec53;
+/// h ^= h >> 33;
+/// ```
+///
+/// with all arithmetic wrapping. The result only depends on the bytes of the
+/// string, not on the platform, the byte order or the version of this crate,
+/// and will not change in future releases.
+///
+/// It isn't keyed, so it gives no protection from HashDoS.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{configure, stable_hash, HashAlgorithm, Interner};
+///
+/// assert_eq!(stable_hash(""), 0xefd01f60ba992926);
+///
+/// let interner =
+///     Interner::with_config(configure().hash_algorithm(HashAlgorithm::Stable))
+///         .unwrap();
+/// let u = interner.intern("hello");
+/// assert_eq!(u.precomputed_hash(), stable_hash("hello"));
+/// ```
+pub fn stable_hash(string: &str) -> u64 {
+    stable_hash_bytes(string.as_bytes())
+}
+
+pub(crate) fn stable_hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+// The hash used by `HashAlgorithm::Stable`, for hashing things other than a
+// single string.
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51afd7ed558ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+        h ^= h >> 33;
+        h
+    }
+}
+
+// A `HashAlgorithm` ready for hashing, with any keys already set up.
+pub(crate) enum StrHasher {
+    AHash,
+    AHashKeyed(ahash::RandomState),
+    Stable,
+    #[cfg(feature = "fxhash")]
+    FxHash,
+    #[cfg(feature = "xxh3")]
+    Xxh3,
+    #[cfg(feature = "siphash")]
+    SipHash13([u64; 2]),
+}
+
+impl StrHasher {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> StrHasher {
+        match algorithm {
+            HashAlgorithm::AHash => StrHasher::AHash,
+            HashAlgorithm::AHashKeyed([k0, k1, k2, k3]) => {
+                StrHasher::AHashKeyed(ahash::RandomState::with_seeds(
+                    k0, k1, k2, k3,
+                ))
+            }
+            HashAlgorithm::Stable => StrHasher::Stable,
+            #[cfg(feature = "fxhash")]
+            HashAlgorithm::FxHash => StrHasher::FxHash,
+            #[cfg(feature = "xxh3")]
+            HashAlgorithm::Xxh3 => StrHasher::Xxh3,
+            #[cfg(feature = "siphash")]
+            HashAlgorithm::SipHash13(key) => StrHasher::SipHash13(key),
+        }
+    }
+
+    // A number identifying the algorithm in snapshots and images. The keys
+    // aren't included, but the check hash stored with the id catches
+    // different keys.
+    pub(crate) fn id(&self) -> u32 {
+        match self {
+            StrHasher::AHash => 1,
+            #[cfg(feature = "fxhash")]
+            StrHasher::FxHash => 2,
+            #[cfg(feature = "xxh3")]
+            StrHasher::Xxh3 => 3,
+            #[cfg(feature = "siphash")]
+            StrHasher::SipHash13(_) => 4,
+            StrHasher::AHashKeyed(_) => 5,
+            StrHasher::Stable => 6,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn hash(&self, bytes: &[u8]) -> u64 {
+        match self {
+            StrHasher::AHash => {
+                let mut hasher = ahash::AHasher::default();
+                hasher.write(bytes);
+                hasher.finish()
+            }
+            StrHasher::AHashKeyed(state) => {
+                let mut hasher = state.build_hasher();
+                hasher.write(bytes);
+                hasher.finish()
+            }
+            StrHasher::Stable => stable_hash_bytes(bytes),
+            #[cfg(feature = "fxhash")]
+            StrHasher::FxHash => {
+                let mut hasher = rustc_hash::FxHasher::default();
+                hasher.write(bytes);
+                hasher.finish()
+            }
+            #[cfg(feature = "xxh3")]
+            StrHasher::Xxh3 => xxhash_rust::xxh3::xxh3_64(bytes),
+            #[cfg(feature = "siphash")]
+            StrHasher::SipHash13([k0, k1]) => {
+                let mut hasher =
+                    siphasher::sip::SipHasher13::new_with_keys(*k0, *k1);
+                hasher.write(bytes);
+                hasher.finish()
+            }
+        }
+    }
+
+    // Hash any value with the same algorithm, e.g. for `Interned`. This goes
+    // through its `Hash` impl, so a string hashed this way doesn't get the
+    // same hash as from `hash()`.
+    pub(crate) fn hash_value<Q: Hash + ?Sized>(&self, value: &Q) -> u64 {
+        fn finish<H: Hasher, Q: Hash + ?Sized>(
+            mut hasher: H,
+            value: &Q,
+        ) -> u64 {
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+        match self {
+            StrHasher::AHash => finish(ahash::AHasher::default(), value),
+            StrHasher::AHashKeyed(state) => finish(state.build_hasher(), value),
+            StrHasher::Stable => finish(StableHasher::default(), value),
+            #[cfg(feature = "fxhash")]
+            StrHasher::FxHash => finish(rustc_hash::FxHasher::default(), value),
+            #[cfg(feature = "xxh3")]
+            StrHasher::Xxh3 => finish(xxhash_rust::xxh3::Xxh3::new(), value),
+            #[cfg(feature = "siphash")]
+            StrHasher::SipHash13([k0, k1]) => finish(
+                siphasher::sip::SipHasher13::new_with_keys(*k0, *k1),
+                value,
+            ),
+        }
+    }
+}
+
 #[test]
 fn test_hashing() {
     let _t = super::TEST_LOCK.lock();
@@ -58,3 +356,81 @@ fn test_hashing() {
     assert_eq!(hm.get(&u1), Some(&17));
     assert_eq!(hm.get(&u2), Some(&42));
 }
+
+#[test]
+fn test_algorithms() {
+    use crate::{configure, Interner};
+
+    let algorithms = [
+        HashAlgorithm::AHash,
+        HashAlgorithm::AHashKeyed([1, 2, 3, 4]),
+        HashAlgorithm::random(),
+        HashAlgorithm::Stable,
+        #[cfg(feature = "fxhash")]
+        HashAlgorithm::FxHash,
+        #[cfg(feature = "xxh3")]
+        HashAlgorithm::Xxh3,
+        #[cfg(feature = "siphash")]
+        HashAlgorithm::SipHash13([1, 2]),
+    ];
+
+    let blns = include_str!("../data/blns.txt");
+    let mut check_hashes = Vec::new();
+    let mut value_hashes = Vec::new();
+    for algorithm in algorithms {
+        let hasher = StrHasher::new(algorithm);
+        check_hashes.push(hasher.hash(b"check"));
+        assert_eq!(hasher.hash(b"check"), hasher.hash(b"check"));
+        value_hashes.push(hasher.hash_value(&(1, "check")));
+        assert_eq!(
+            hasher.hash_value(&(1, "check")),
+            hasher.hash_value(&(1, "check"))
+        );
+
+        let interner =
+            Interner::with_config(configure().hash_algorithm(algorithm))
+                .unwrap();
+        for s in blns.split_whitespace() {
+            let i = interner.intern(s);
+            assert_eq!(i.precomputed_hash(), hasher.hash(s.as_bytes()));
+            assert_eq!(interner.get(s), Some(i));
+        }
+    }
+    // Every algorithm gives different hashes.
+    let n = check_hashes.len();
+    check_hashes.sort_unstable();
+    check_hashes.dedup();
+    assert_eq!(check_hashes.len(), n);
+    value_hashes.sort_unstable();
+    value_hashes.dedup();
+    assert_eq!(value_hashes.len(), n);
+}
+
+#[test]
+fn test_stable_hash_vectors() {
+    use crate::{configure, Interner};
+
+    // These must never change.
+    let vectors = [
+        ("", 0xefd01f60ba992926),
+        ("a", 0x82a2a958a9bece5b),
+        ("hello", 0xe9c562c0fdb23244),
+        ("ustr", 0xdb2b1ccd9204cd05),
+        (
+            "The quick brown fox jumps over the lazy dog",
+            0x845b9fc148948e6b,
+        ),
+        ("héllo wörld", 0xc9908fbae14ae724),
+        ("日本語", 0xe11518acc326d0e8),
+        ("🦀", 0x940aed07730b2626),
+        ("a\0b", 0xab78f5eca36d0e2b),
+    ];
+    let interner = Interner::with_config(
+        configure().hash_algorithm(HashAlgorithm::Stable),
+    )
+    .unwrap();
+    for (s, hash) in vectors {
+        assert_eq!(stable_hash(s), hash, "{:?}", s);
+        assert_eq!(interner.intern(s).precomputed_hash(), hash, "{:?}", s);
+    }
+}
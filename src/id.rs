@@ -0,0 +1,308 @@
+use super::{limits::InternError, StringCacheEntry, Ustr};
+use std::{
+    fmt,
+    num::NonZeroU32,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+};
+
+/// A compact, 4-byte identifier for a [`Ustr`].
+///
+/// Every string in the global cache is given a dense id when it is first
+/// interned, counting up from 1 in insertion order with no gaps. The id of a
+/// string stays the same until the cache is cleared with
+/// [`_clear_cache`](crate::_clear_cache), which starts the ids from 1 again.
+/// Ids will generally be different in another process, so don't write them to
+/// files without also writing out the strings they refer to.
+///
+/// This needs the `"ids"` feature, which adds 4 bytes (8 with alignment) to
+/// each entry in the cache.
+///
+/// `Option<UstrId>` is the same size as `UstrId`.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ustr, Ustr, UstrId};
+///
+/// let u = ustr("identifier");
+/// let id: UstrId = u.id();
+/// assert_eq!(Ustr::from_id(id.get()), Some(u));
+/// assert_eq!(id.to_ustr(), Some(u));
+/// assert_eq!(std::mem::size_of::<Option<UstrId>>(), 4);
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct UstrId(NonZeroU32);
+
+impl UstrId {
+    /// Wrap a raw id, returning `None` if it is 0.
+    ///
+    /// This does not check that a string with the given id exists.
+    pub fn new(id: u32) -> Option<UstrId> {
+        NonZeroU32::new(id).map(UstrId)
+    }
+
+    /// Get the raw id.
+    #[inline]
+    pub fn get(self) -> u32 {
+        self.0.get()
+    }
+
+    /// Look up the `Ustr` with this id, or `None` if no string with this id
+    /// has been interned.
+    #[inline]
+    pub fn to_ustr(self) -> Option<Ustr> {
+        Ustr::from_id(self.get())
+    }
+}
+
+impl From<Ustr> for UstrId {
+    fn from(u: Ustr) -> UstrId {
+        u.id()
+    }
+}
+
+impl From<UstrId> for u32 {
+    fn from(id: UstrId) -> u32 {
+        id.get()
+    }
+}
+
+impl From<UstrId> for NonZeroU32 {
+    fn from(id: UstrId) -> NonZeroU32 {
+        id.0
+    }
+}
+
+impl fmt::Debug for UstrId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UstrId({})", self.get())
+    }
+}
+
+impl fmt::Display for UstrId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+// The id table is an append-only array of pointers to entries, indexed by id.
+// It's split into buckets that double in size so that it can grow without
+// ever moving an existing slot, which means readers never need to take a lock:
+// they just load the bucket pointer and then the slot. Writers hold the lock
+// for the bin the string is going into, but different bins can publish ids
+// concurrently, so buckets are created with a compare-and-swap.
+//
+// Id `n` lives at index `n + FIRST_BUCKET_SIZE` counted across all buckets, so
+// that bucket `b` holds `FIRST_BUCKET_SIZE << b` slots. This gives us enough
+// buckets for every u32.
+const FIRST_BUCKET_SHIFT: u32 = 5;
+const FIRST_BUCKET_SIZE: usize = 1 << FIRST_BUCKET_SHIFT;
+const NUM_BUCKETS: usize = (u32::BITS + 1 - FIRST_BUCKET_SHIFT) as usize;
+
+pub(crate) struct IdTable {
+    // The next id to hand out.
+    next: AtomicU32,
+    buckets: [AtomicPtr<AtomicPtr<StringCacheEntry>>; NUM_BUCKETS],
+}
+
+// Find the bucket and the index within it for the given id.
+#[inline]
+fn locate(id: u32) -> (usize, usize) {
+    let n = id as u64 + FIRST_BUCKET_SIZE as u64;
+    let bucket =
+        (u64::BITS - 1 - n.leading_zeros() - FIRST_BUCKET_SHIFT) as usize;
+    let index = (n - ((FIRST_BUCKET_SIZE as u64) << bucket)) as usize;
+    (bucket, index)
+}
+
+#[inline]
+fn bucket_size(bucket: usize) -> usize {
+    FIRST_BUCKET_SIZE << bucket
+}
+
+impl IdTable {
+    pub(crate) fn new() -> IdTable {
+        IdTable {
+            next: AtomicU32::new(1),
+            buckets: Default::default(),
+        }
+    }
+
+    // Hand out the next id. Its slot is made before the id is taken, so
+    // that `publish()` can't fail and running out of memory here doesn't
+    // leave a gap in the ids.
+    pub(crate) fn reserve(&self) -> Result<NonZeroU32, InternError> {
+        let mut id = self.next.load(Ordering::Relaxed);
+        loop {
+            // We start at 1 and never wrap around.
+            let next = id.checked_add(1).ok_or(InternError::TooManyEntries)?;
+            self.prepare(id)?;
+            match self.next.compare_exchange_weak(
+                id,
+                next,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(NonZeroU32::new(id).expect("id was zero")),
+                Err(current) => id = current,
+            }
+        }
+    }
+
+    // Allocate the bucket for the given id if it doesn't exist yet.
+    fn prepare(&self, id: u32) -> Result<(), InternError> {
+        let (bucket, _) = locate(id);
+        if !self.buckets[bucket].load(Ordering::Acquire).is_null() {
+            return Ok(());
+        }
+        let mut new_slots: Vec<AtomicPtr<StringCacheEntry>> = Vec::new();
+        new_slots
+            .try_reserve_exact(bucket_size(bucket))
+            .map_err(|_| InternError::OutOfMemory)?;
+        new_slots.resize_with(bucket_size(bucket), || {
+            AtomicPtr::new(ptr::null_mut())
+        });
+        let new_slots =
+            Box::into_raw(new_slots.into_boxed_slice()) as *mut AtomicPtr<_>;
+        if self.buckets[bucket]
+            .compare_exchange(
+                ptr::null_mut(),
+                new_slots,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            // Another bin got there first.
+            unsafe { free_bucket(new_slots, bucket) };
+        }
+        Ok(())
+    }
+
+    // Make the entry visible to `get()`. The entry must be fully written and
+    // the id must have come from `reserve()`.
+    pub(crate) fn publish(&self, id: NonZeroU32, entry: *mut StringCacheEntry) {
+        let (bucket, index) = locate(id.get());
+        let slots = self.buckets[bucket].load(Ordering::Acquire);
+        debug_assert!(!slots.is_null());
+        // This is safe as `index` is always within the bucket.
+        unsafe { (*slots.add(index)).store(entry, Ordering::Release) };
+    }
+
+    // Get the entry with the given id, if there is one.
+    #[inline]
+    pub(crate) fn get(&self, id: u32) -> Option<NonNull<StringCacheEntry>> {
+        if id == 0 {
+            return None;
+        }
+        let (bucket, index) = locate(id);
+        let slots = self.buckets[bucket].load(Ordering::Acquire);
+        if slots.is_null() {
+            return None;
+        }
+        // This is safe as `index` is always within the bucket.
+        NonNull::new(unsafe { (*slots.add(index)).load(Ordering::Acquire) })
+    }
+
+    // Forget all the ids, used when clearing the cache. Must not be called
+    // while anything else is using the table.
+    pub(crate) unsafe fn clear(&self) {
+        for (bucket, slots) in self.buckets.iter().enumerate() {
+            let slots = slots.swap(ptr::null_mut(), Ordering::AcqRel);
+            if !slots.is_null() {
+                free_bucket(slots, bucket);
+            }
+        }
+        self.next.store(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for IdTable {
+    fn drop(&mut self) {
+        unsafe { self.clear() }
+    }
+}
+
+unsafe fn free_bucket(slots: *mut AtomicPtr<StringCacheEntry>, bucket: usize) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+        slots,
+        bucket_size(bucket),
+    )));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket_size, locate, IdTable, NUM_BUCKETS};
+    use crate::StringCacheEntry;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(locate(0), (0, 0));
+        assert_eq!(locate(1), (0, 1));
+        assert_eq!(locate(31), (0, 31));
+        assert_eq!(locate(32), (1, 0));
+        assert_eq!(locate(95), (1, 63));
+        assert_eq!(locate(96), (2, 0));
+        let (bucket, index) = locate(u32::MAX);
+        assert_eq!(bucket, NUM_BUCKETS - 1);
+        assert!(index < bucket_size(bucket));
+    }
+
+    #[test]
+    fn publish_and_get() {
+        let table = IdTable::new();
+        let mut entries: Vec<StringCacheEntry> = (0..100)
+            .map(|n| StringCacheEntry {
+                id: 0,
+                #[cfg(feature = "meta")]
+                meta: Default::default(),
+                #[cfg(feature = "char-counts")]
+                char_count: 0,
+                #[cfg(feature = "char-counts")]
+                utf16_len: 0,
+                hash: n,
+                len: 0,
+            })
+            .collect();
+        let mut ids = Vec::new();
+        for e in entries.iter_mut() {
+            let id = table.reserve().unwrap();
+            e.id = id.get();
+            table.publish(id, e);
+            ids.push(id);
+        }
+        assert_eq!(ids[0].get(), 1);
+        assert_eq!(ids[99].get(), 100);
+        for (n, id) in ids.iter().enumerate() {
+            let e = table.get(id.get()).unwrap();
+            assert_eq!(unsafe { e.as_ref().hash }, n as u64);
+        }
+        assert!(table.get(0).is_none());
+        assert!(table.get(101).is_none());
+        assert!(table.get(u32::MAX).is_none());
+    }
+
+    #[test]
+    fn no_gaps() {
+        let table = IdTable::new();
+        let mut ids: Vec<u32> = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        (0..500)
+                            .map(|_| table.reserve().unwrap().get())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|t| t.join().unwrap())
+                .collect()
+        });
+        ids.sort_unstable();
+        assert_eq!(ids, (1..=2000).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,720 @@
+//! Cache images: files that hold strings in exactly the layout the cache
+//! uses in memory, so that they can be registered with the global cache
+//! without copying them.
+//!
+//! Where a [snapshot](crate::snapshot) has to be read and every string
+//! interned again, an image only has to be checked and linked into the
+//! cache's tables. With the `"mmap"` feature, `map()` maps an image file
+//! straight into memory, so processes that all start with the same large set
+//! of strings can share one copy of them through the page cache.
+//!
+//! `Ustr`s that point into an image behave exactly like ones that point into
+//! the cache's own storage. If a string in the image is already in the cache
+//! when it's registered, the existing copy is kept and the one in the image
+//! is ignored.
+//!
+//! # Examples
+//!
+//! ```
+//! use ustr::ustr;
+//!
+//! ustr("Map me");
+//! let mut bytes = Vec::new();
+//! ustr::image::write(&mut bytes).unwrap();
+//!
+//! // ... in another process ...
+//! # unsafe { ustr::_clear_cache() };
+//! // Images have to be 8-byte aligned, which a `Vec<u8>` isn't guaranteed to
+//! // be, so copy it into a `Vec<u64>`.
+//! let mut words = vec![0u64; (bytes.len() + 7) / 8];
+//! let image: &'static mut [u8] = unsafe {
+//!     std::slice::from_raw_parts_mut(
+//!         Box::leak(words.into_boxed_slice()).as_mut_ptr() as *mut u8,
+//!         bytes.len(),
+//!     )
+//! };
+//! image.copy_from_slice(&bytes);
+//! assert_eq!(ustr::image::register(image).unwrap(), 1);
+//! assert!(ustr::existing_ustr("Map me").is_some());
+//! ```
+//!
+//! # Format
+//!
+//! Since an image is used in place, all integers are in the byte order of
+//! the machine that wrote it, and images can only be registered on machines
+//! with the same byte order and pointer width.
+//!
+//! | Field      | Size      | Contents                                      |
+//! |------------|-----------|-----------------------------------------------|
+//! | magic      | 8         | `b"USTRIMG\0"`                                |
+//! | version    | 4         | format version, currently 1                   |
+//! | byte order | 4         | `0x01020304`                                  |
+//! | entry size | 4         | size of the header before each string         |
+//! | word size  | 4         | size of a `usize`                             |
+//! | algorithm  | 4         | id of the hash function used for the hashes  |
+//! | reserved   | 4         | 0                                             |
+//! | check      | 8         | hash of [`HASH_CHECK_STRING`]                 |
+//! | count      | 8         | number of strings                             |
+//! | length     | 8         | total size of the strings in bytes            |
+//! | strings    | length    | the strings, each 8-byte aligned              |
+//!
+//! Each string is stored as a header with an 8-byte hash and a `usize`
+//! length, followed by the bytes of the string, a null terminator, and zeros
+//! up to the next multiple of 8 bytes. With the `"ids"` feature, the header
+//! starts with a 4-byte id (always 0) and 4 bytes of padding. With the
+//! `"meta"` feature, it also has an 8-byte metadata word (always 0) before
+//! the hash, and with the `"char-counts"` feature, the string's `usize` char
+//! count and UTF-16 length come next. Images can only be registered by builds
+//! that agree on these features.
+//!
+//! Everything in an image is computed when it's written, so registering one
+//! never writes to it. It has to have been written with the same hash
+//! function as the cache it's registered with.
+pub use super::snapshot::HASH_CHECK_STRING;
+#[cfg(feature = "char-counts")]
+use super::stringcache::char_counts;
+use super::{round_up_to, Bins, InternError, StringCacheEntry, STRING_CACHE};
+use byteorder::{ByteOrder, NativeEndian, WriteBytesExt};
+#[cfg(feature = "ids")]
+use std::sync::atomic::AtomicU32;
+#[cfg(feature = "meta")]
+use std::sync::atomic::AtomicU64;
+use std::{
+    fmt,
+    io::{self, Write},
+    mem::{align_of, size_of, MaybeUninit},
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+const MAGIC: &[u8; 8] = b"USTRIMG\0";
+const VERSION: u32 = 1;
+const BYTE_ORDER: u32 = 0x01020304;
+// Size of everything before the strings.
+const HEADER_LEN: usize = 56;
+const ENTRY_HEADER_LEN: usize = size_of::<StringCacheEntry>();
+const ALIGN: usize = align_of::<StringCacheEntry>();
+
+/// Error returned when registering a cache image.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ImageError {
+    /// Reading the image failed.
+    Io(io::Error),
+    /// The data doesn't start with the image magic bytes.
+    BadMagic,
+    /// The image was written with an unsupported format version.
+    UnsupportedVersion(u32),
+    /// The image was written on a machine with a different byte order or
+    /// pointer width, or by a build with a different entry layout.
+    IncompatibleLayout,
+    /// The image doesn't start on an 8-byte boundary.
+    Misaligned,
+    /// The data ended before the end of the image.
+    Truncated,
+    /// There is more data after the strings than the header says.
+    TrailingData,
+    /// A string in the image is not valid UTF-8.
+    InvalidUtf8,
+    /// The image was written with a different hash function from the one
+    /// the cache uses.
+    HashMismatch,
+    /// The header of a string in the image doesn't match the string, or the
+    /// string isn't followed by a null terminator.
+    BadEntry,
+    /// A string in the image could not be added to the cache.
+    Intern(InternError),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "error reading cache image: {}", e),
+            ImageError::BadMagic => write!(f, "not a ustr cache image"),
+            ImageError::UnsupportedVersion(v) => {
+                write!(f, "unsupported cache image version {}", v)
+            }
+            ImageError::IncompatibleLayout => {
+                write!(f, "cache image was written for a different platform")
+            }
+            ImageError::Misaligned => {
+                write!(f, "cache image is not 8-byte aligned")
+            }
+            ImageError::Truncated => write!(f, "cache image is truncated"),
+            ImageError::TrailingData => {
+                write!(f, "cache image has data after the strings")
+            }
+            ImageError::InvalidUtf8 => {
+                write!(f, "cache image contains invalid UTF-8")
+            }
+            ImageError::HashMismatch => {
+                write!(
+                    f,
+                    "cache image was written with a different hash function"
+                )
+            }
+            ImageError::BadEntry => {
+                write!(f, "cache image contains a corrupt string entry")
+            }
+            ImageError::Intern(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::Io(e) => Some(e),
+            ImageError::Intern(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
+
+impl From<InternError> for ImageError {
+    fn from(e: InternError) -> Self {
+        ImageError::Intern(e)
+    }
+}
+
+/// Write every string in the global cache to `writer` as a cache image.
+///
+/// The strings written are exactly those that were in the cache at one point
+/// in time, so if other threads are adding strings while this runs, any
+/// string that's included comes with every string added before it.
+pub fn write<W: Write>(writer: &mut W) -> io::Result<()> {
+    write_bins(&STRING_CACHE, writer)
+}
+
+/// Add the strings in a cache image to the global cache, returning the number
+/// of strings that weren't in the cache already.
+///
+/// The strings are used in place rather than copied, which is why the image
+/// has to live forever. The image is only ever read: the ids and metadata of
+/// its strings are kept in a table on the side.
+///
+/// The whole image is checked before anything is added, including every
+/// string's stored hash, so an image that was written with a different hash
+/// function or has been corrupted is rejected rather than fixed up. If adding
+/// a string fails, e.g. because the cache is at its
+/// [`max_entries`](crate::CacheConfig::max_entries) limit, the strings before
+/// it stay in the cache.
+pub fn register(image: &'static [u8]) -> Result<usize, ImageError> {
+    register_bins(&STRING_CACHE, image)
+}
+
+/// Map the cache image file at `path` into memory and add its strings to the
+/// global cache, returning the number of strings that weren't in the cache
+/// already.
+///
+/// The file is mapped read-only, so every process that maps it shares the
+/// same pages. The mapping is never unmapped since the strings in it live
+/// forever.
+///
+/// # Safety
+///
+/// The file must not be modified by this or any other process while it is
+/// mapped, since changes to it would show up in the mapping, and so in
+/// `Ustr`s pointing into it.
+#[cfg(feature = "mmap")]
+pub unsafe fn map<P: AsRef<std::path::Path>>(
+    path: P,
+) -> Result<usize, ImageError> {
+    let file = std::fs::File::open(path)?;
+    let mmap = memmap2::MmapOptions::new().map(&file)?;
+    let mmap: &'static memmap2::Mmap = Box::leak(Box::new(mmap));
+    register(mmap)
+}
+
+pub(crate) fn write_bins<W: Write>(
+    bins: &Bins,
+    writer: &mut W,
+) -> io::Result<()> {
+    let iter = bins.iter_consistent();
+    let mut count = 0;
+    let mut length = 0;
+    for s in iter.clone() {
+        count += 1;
+        length += ENTRY_HEADER_LEN + padded_len(s.len());
+    }
+
+    writer.write_all(MAGIC)?;
+    writer.write_u32::<NativeEndian>(VERSION)?;
+    writer.write_u32::<NativeEndian>(BYTE_ORDER)?;
+    writer.write_u32::<NativeEndian>(ENTRY_HEADER_LEN as u32)?;
+    writer.write_u32::<NativeEndian>(size_of::<usize>() as u32)?;
+    writer.write_u32::<NativeEndian>(bins.hasher.id())?;
+    writer.write_u32::<NativeEndian>(0)?;
+    writer.write_u64::<NativeEndian>(bins.hash(HASH_CHECK_STRING))?;
+    writer.write_u64::<NativeEndian>(count as u64)?;
+    writer.write_u64::<NativeEndian>(length as u64)?;
+    let zeros = [0u8; ALIGN];
+    for s in iter.take(count) {
+        let sce = unsafe { StringCacheEntry::from_char_ptr(s.as_ptr()) };
+        writer.write_all(&entry_header(sce))?;
+        writer.write_all(s.as_bytes())?;
+        // The null terminator and the padding.
+        writer.write_all(&zeros[..padded_len(s.len()) - s.len()])?;
+    }
+    Ok(())
+}
+
+pub(crate) fn register_bins(
+    bins: &Bins,
+    image: &'static [u8],
+) -> Result<usize, ImageError> {
+    if image.as_ptr() as usize & (ALIGN - 1) != 0 {
+        return Err(ImageError::Misaligned);
+    }
+    if image.len() < MAGIC.len() || &image[..MAGIC.len()] != MAGIC {
+        return Err(ImageError::BadMagic);
+    }
+    if image.len() < HEADER_LEN {
+        return Err(ImageError::Truncated);
+    }
+    if NativeEndian::read_u32(&image[12..16]) != BYTE_ORDER {
+        return Err(ImageError::IncompatibleLayout);
+    }
+    let version = NativeEndian::read_u32(&image[8..12]);
+    if version != VERSION {
+        return Err(ImageError::UnsupportedVersion(version));
+    }
+    if NativeEndian::read_u32(&image[16..20]) as usize != ENTRY_HEADER_LEN
+        || NativeEndian::read_u32(&image[20..24]) as usize != size_of::<usize>()
+    {
+        return Err(ImageError::IncompatibleLayout);
+    }
+    let algorithm = NativeEndian::read_u32(&image[24..28]);
+    let check = NativeEndian::read_u64(&image[32..40]);
+    let count = NativeEndian::read_u64(&image[40..48]);
+    let length = NativeEndian::read_u64(&image[48..56]);
+    if algorithm != bins.hasher.id() || check != bins.hash(HASH_CHECK_STRING) {
+        return Err(ImageError::HashMismatch);
+    }
+
+    let strings = &image[HEADER_LEN..];
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|length| *length <= strings.len())
+        .ok_or(ImageError::Truncated)?;
+    if length < strings.len() {
+        return Err(ImageError::TrailingData);
+    }
+
+    // Check the whole thing before linking anything in. The image is never
+    // written to, so every entry has to be exactly what the cache would have
+    // made for its string.
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    for _ in 0..count {
+        if strings.len() - offset < ENTRY_HEADER_LEN {
+            return Err(ImageError::Truncated);
+        }
+        // This is safe as `offset` is a multiple of the alignment (the header
+        // is too) and there's room for the header. Any bits are valid for
+        // the header's fields.
+        let entry = unsafe {
+            &*(strings.as_ptr().add(offset) as *const StringCacheEntry)
+        };
+        let chars = offset + ENTRY_HEADER_LEN;
+        let len = entry.len;
+        let next = len
+            .checked_add(1)
+            .and_then(|n| round_up_to(n, ALIGN))
+            .and_then(|n| n.checked_add(chars))
+            .filter(|next| *next <= strings.len())
+            .ok_or(ImageError::Truncated)?;
+        let s = std::str::from_utf8(&strings[chars..chars + len])
+            .map_err(|_| ImageError::InvalidUtf8)?;
+        if strings[chars + len] != 0
+            || entry.hash != bins.hash(s)
+            || !header_is_fresh(entry)
+        {
+            return Err(ImageError::BadEntry);
+        }
+        offsets.push(offset);
+        offset = next;
+    }
+    if offset != strings.len() {
+        return Err(ImageError::TrailingData);
+    }
+    if offsets.is_empty() {
+        return Ok(0);
+    }
+
+    // Make the image known before linking its entries, so that the state
+    // kept for them outside the image can be found as soon as they can be
+    // looked up.
+    let region: &'static ImageRegion = Box::leak(Box::new(ImageRegion {
+        start: strings.as_ptr(),
+        #[cfg(any(feature = "ids", feature = "meta"))]
+        end: strings.as_ptr_range().end,
+        slots: offsets.iter().map(|_| ImageSlot::default()).collect(),
+        offsets: offsets.into_boxed_slice(),
+    }));
+    bins.add_image(region);
+
+    let mut linked = 0;
+    for (entry, slot) in region.entries() {
+        let hash = entry.hash;
+        // This is safe as we've checked every entry above, and the image
+        // lives forever.
+        let result = unsafe { bins.bin(hash).lock().link(entry, slot, bins) };
+        // If this fails, the entries before it stay linked in.
+        if result? {
+            linked += 1;
+        }
+    }
+    Ok(linked)
+}
+
+// Check that the parts of an entry's header that the cache fills in as it
+// goes are as they would be for a new entry.
+fn header_is_fresh(entry: &StringCacheEntry) -> bool {
+    #[cfg(feature = "char-counts")]
+    if (entry.char_count, entry.utf16_len) != char_counts(entry.as_bytes()) {
+        return false;
+    }
+    #[cfg(feature = "meta")]
+    if entry.meta.load(Ordering::Relaxed) != 0 {
+        return false;
+    }
+    #[cfg(feature = "ids")]
+    if entry.id != 0 {
+        return false;
+    }
+    let _ = entry;
+    true
+}
+
+// Size of a string with its null terminator and padding.
+fn padded_len(len: usize) -> usize {
+    round_up_to(len + 1, ALIGN).expect("string length overflowed")
+}
+
+// The bytes of the header for a string in an image, which is a copy of its
+// header in the cache without the parts that only mean something in this
+// process.
+fn entry_header(sce: &StringCacheEntry) -> [u8; ENTRY_HEADER_LEN] {
+    // Start from zeros so that the padding in the header is initialized.
+    let mut header = MaybeUninit::<StringCacheEntry>::zeroed();
+    let p = header.as_mut_ptr();
+    unsafe {
+        #[cfg(feature = "ids")]
+        addr_of_mut!((*p).id).write(0);
+        #[cfg(feature = "char-counts")]
+        {
+            addr_of_mut!((*p).char_count).write(sce.char_count);
+            addr_of_mut!((*p).utf16_len).write(sce.utf16_len);
+        }
+        addr_of_mut!((*p).hash).write(sce.hash);
+        addr_of_mut!((*p).len).write(sce.len);
+        *(p as *const [u8; ENTRY_HEADER_LEN])
+    }
+}
+
+// A registered image, along with the state of its entries that belongs to
+// this process and so can't be kept in the image itself.
+pub(crate) struct ImageRegion {
+    start: *const u8,
+    #[cfg(any(feature = "ids", feature = "meta"))]
+    end: *const u8,
+    // Offset of each entry from `start`, in order.
+    offsets: Box<[usize]>,
+    slots: Box<[ImageSlot]>,
+}
+
+#[derive(Default)]
+pub(crate) struct ImageSlot {
+    // Whether the entry was linked in, which it isn't if the cache already
+    // had its string.
+    pub(crate) linked: AtomicBool,
+    #[cfg(feature = "ids")]
+    pub(crate) id: AtomicU32,
+    #[cfg(feature = "meta")]
+    pub(crate) meta: AtomicU64,
+}
+
+impl ImageRegion {
+    #[cfg(any(feature = "ids", feature = "meta"))]
+    pub(crate) fn contains(&self, entry: *const StringCacheEntry) -> bool {
+        (self.start..self.end).contains(&(entry as *const u8))
+    }
+
+    // Get the slot for an entry in this image.
+    #[cfg(any(feature = "ids", feature = "meta"))]
+    pub(crate) fn slot(&self, entry: *const StringCacheEntry) -> &ImageSlot {
+        let offset = entry as usize - self.start as usize;
+        let index = self
+            .offsets
+            .binary_search(&offset)
+            .expect("pointer is not to an entry in the image");
+        &self.slots[index]
+    }
+
+    // The entries in the image with their slots.
+    pub(crate) fn entries(
+        &self,
+    ) -> impl Iterator<Item = (&StringCacheEntry, &ImageSlot)> {
+        // This is safe as the offsets were checked when the image was
+        // registered.
+        self.offsets
+            .iter()
+            .zip(self.slots.iter())
+            .map(|(offset, slot)| {
+                let entry = unsafe {
+                    &*(self.start.add(*offset) as *const StringCacheEntry)
+                };
+                (entry, slot)
+            })
+    }
+
+    // The number of entries in the image.
+    pub(crate) fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    // The string of the entry with the given index, if it was linked into
+    // the cache.
+    pub(crate) fn linked_str(&self, index: usize) -> Option<&'static str> {
+        if !self.slots[index].linked.load(Ordering::Relaxed) {
+            return None;
+        }
+        // This is safe as the offsets were checked when the image was
+        // registered, and the image lives forever.
+        let entry = unsafe {
+            &*(self.start.add(self.offsets[index]) as *const StringCacheEntry)
+        };
+        Some(entry.as_str())
+    }
+}
+
+// The image is only ever read through, and lives forever.
+unsafe impl Send for ImageRegion {}
+unsafe impl Sync for ImageRegion {}
+
+#[cfg(test)]
+mod tests {
+    use super::{register, write, ImageError, ENTRY_HEADER_LEN, HEADER_LEN};
+    use crate::{
+        existing_ustr, num_entries, string_cache_iter, total_allocated, ustr,
+    };
+    use std::collections::HashSet;
+
+    // Copy the bytes into 8-byte aligned memory that lives forever.
+    fn leak_aligned(bytes: &[u8]) -> &'static [u8] {
+        let words = vec![0u64; bytes.len() / 8 + 1];
+        let image = unsafe {
+            std::slice::from_raw_parts_mut(
+                Box::leak(words.into_boxed_slice()).as_mut_ptr() as *mut u8,
+                bytes.len(),
+            )
+        };
+        image.copy_from_slice(bytes);
+        image
+    }
+
+    #[test]
+    // We have to disable miri here as it's far too slow unfortunately
+    #[cfg_attr(miri, ignore)]
+    fn round_trip() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        let blns = include_str!("../data/blns.txt");
+        let expected: HashSet<&str> = blns.split_whitespace().collect();
+        for s in blns.split_whitespace() {
+            ustr(s);
+        }
+        let mut bytes = Vec::new();
+        write(&mut bytes).unwrap();
+
+        unsafe { crate::_clear_cache() };
+        let allocated = total_allocated();
+        let image = leak_aligned(&bytes);
+        let range = image.as_ptr_range();
+        assert_eq!(register(image).unwrap(), expected.len());
+        assert_eq!(num_entries(), expected.len());
+        // Nothing was copied into the cache's own storage.
+        assert_eq!(total_allocated(), allocated);
+        // Nor was anything written to the image.
+        assert_eq!(image, &bytes[..]);
+
+        let found: Vec<&str> = string_cache_iter().collect();
+        assert_eq!(found.len(), expected.len());
+        assert_eq!(found.into_iter().collect::<HashSet<_>>(), expected);
+        for s in &expected {
+            let u = existing_ustr(s).unwrap();
+            assert!(range.contains(&(u.as_char_ptr() as *const u8)));
+            assert_eq!(u, ustr(s));
+            assert_eq!(u.as_str(), *s);
+            assert_eq!(u.as_cstr().to_bytes(), s.as_bytes());
+            #[cfg(feature = "ids")]
+            assert_eq!(crate::Ustr::from_id(u.id().get()), Some(u));
+        }
+    }
+
+    #[test]
+    fn already_interned() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        for s in ["a", "b", "c"] {
+            ustr(s);
+        }
+        let mut bytes = Vec::new();
+        write(&mut bytes).unwrap();
+
+        unsafe { crate::_clear_cache() };
+        let a = ustr("a");
+        let image = leak_aligned(&bytes);
+        let range = image.as_ptr_range();
+        assert_eq!(register(image).unwrap(), 2);
+        assert_eq!(num_entries(), 3);
+        assert_eq!(ustr("a"), a);
+        assert!(!range.contains(&(a.as_char_ptr() as *const u8)));
+        assert!(range.contains(&(ustr("b").as_char_ptr() as *const u8)));
+
+        let mut found: Vec<&str> = string_cache_iter().collect();
+        found.sort_unstable();
+        assert_eq!(found, ["a", "b", "c"]);
+
+        // Registering the same strings again adds nothing.
+        assert_eq!(register(leak_aligned(&bytes)).unwrap(), 0);
+        assert_eq!(string_cache_iter().count(), 3);
+    }
+
+    #[test]
+    fn invalid() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+        ustr("hello");
+        ustr("world");
+
+        let mut bytes = Vec::new();
+        write(&mut bytes).unwrap();
+        unsafe { crate::_clear_cache() };
+
+        let misaligned = leak_aligned(&[&[0][..], &bytes].concat());
+        assert!(matches!(
+            register(&misaligned[1..]),
+            Err(ImageError::Misaligned)
+        ));
+        assert!(matches!(
+            register(leak_aligned(&bytes[..bytes.len() - 8])),
+            Err(ImageError::Truncated)
+        ));
+        assert!(matches!(
+            register(leak_aligned(&[&bytes[..], &[0; 8]].concat())),
+            Err(ImageError::TrailingData)
+        ));
+        assert!(matches!(
+            register(leak_aligned(b"not a cache image")),
+            Err(ImageError::BadMagic)
+        ));
+
+        let mut bad_utf8 = bytes.clone();
+        // The first byte of the first string.
+        bad_utf8[HEADER_LEN + ENTRY_HEADER_LEN] = 0xff;
+        assert!(matches!(
+            register(leak_aligned(&bad_utf8)),
+            Err(ImageError::InvalidUtf8)
+        ));
+
+        let mut other_version = bytes.clone();
+        other_version[8] ^= 0x80;
+        assert!(matches!(
+            register(leak_aligned(&other_version)),
+            Err(ImageError::UnsupportedVersion(_))
+        ));
+
+        let mut other_hash = bytes.clone();
+        other_hash[32] ^= 1;
+        assert!(matches!(
+            register(leak_aligned(&other_hash)),
+            Err(ImageError::HashMismatch)
+        ));
+
+        // The hash of the first string, just before its length.
+        let mut bad_hash = bytes.clone();
+        bad_hash[HEADER_LEN + ENTRY_HEADER_LEN - 16] ^= 1;
+        assert!(matches!(
+            register(leak_aligned(&bad_hash)),
+            Err(ImageError::BadEntry)
+        ));
+
+        // Ids are assigned when an image is registered, never stored in it.
+        #[cfg(feature = "ids")]
+        {
+            let mut with_id = bytes.clone();
+            with_id[HEADER_LEN] = 1;
+            assert!(matches!(
+                register(leak_aligned(&with_id)),
+                Err(ImageError::BadEntry)
+            ));
+        }
+
+        let mut unterminated = bytes.clone();
+        unterminated[HEADER_LEN + ENTRY_HEADER_LEN + 5] = b'!';
+        assert!(matches!(
+            register(leak_aligned(&unterminated)),
+            Err(ImageError::BadEntry)
+        ));
+
+        // Nothing was added by the failed registrations.
+        assert_eq!(num_entries(), 0);
+        assert_eq!(string_cache_iter().count(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "meta")]
+    fn meta() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+        ustr("hello");
+        ustr("world");
+
+        let mut bytes = Vec::new();
+        write(&mut bytes).unwrap();
+        unsafe { crate::_clear_cache() };
+
+        let image = leak_aligned(&bytes);
+        register(image).unwrap();
+        let hello = ustr("hello");
+        assert!(image
+            .as_ptr_range()
+            .contains(&(hello.as_char_ptr() as *const u8)));
+        assert_eq!(hello.meta(), 0);
+        hello.set_meta(7);
+        assert_eq!(hello.compare_exchange_meta(7, 8), Ok(7));
+        assert_eq!(ustr("hello").meta(), 8);
+        assert_eq!(ustr("world").meta(), 0);
+        assert_eq!(image, &bytes[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    #[cfg_attr(miri, ignore)]
+    fn map() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+        ustr("mapped");
+
+        let path = std::env::temp_dir()
+            .join(format!("ustr-image-test-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        write(&mut file).unwrap();
+        drop(file);
+
+        unsafe { crate::_clear_cache() };
+        assert_eq!(unsafe { super::map(&path) }.unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(existing_ustr("mapped").unwrap().as_str(), "mapped");
+    }
+}
@@ -0,0 +1,359 @@
+use super::{stringcache::NUM_BINS, IdentityHasher, STRING_CACHE};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    any::{Any, TypeId},
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasherDefault, Hash, Hasher},
+    ops::Deref,
+};
+
+/// A handle to a value of any type in its own global, hash-consed pool.
+///
+/// This does for any `Hash + Eq` type what [`Ustr`](crate::Ustr) does for
+/// strings: every equal value is stored once, so handles are a single
+/// pointer, compare by pointer and hash with a hash computed when the value
+/// was interned. That makes them work with [`InternedMap`] and
+/// [`InternedSet`], which use the precomputed hash directly.
+///
+/// [`InternedMap`]: crate::InternedMap
+/// [`InternedSet`]: crate::InternedSet
+///
+/// Each type gets its own pool, which is sharded by hash to cut down on lock
+/// contention. Values are hashed with the global cache's configured
+/// [`HashAlgorithm`](crate::HashAlgorithm). Like strings in the global cache,
+/// interned values are never dropped.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ustr, Interned, Ustr};
+///
+/// #[derive(Debug, Hash, PartialEq, Eq)]
+/// enum Expr {
+///     Var(Ustr),
+///     Add(Interned<Expr>, Interned<Expr>),
+/// }
+///
+/// let x = Interned::new(Expr::Var(ustr("x")));
+/// let sum = Interned::new(Expr::Add(x, x));
+/// assert_eq!(sum, Interned::new(Expr::Add(x, Interned::new(Expr::Var(ustr("x"))))));
+/// assert!(matches!(*sum, Expr::Add(a, _) if a == x));
+///
+/// let pair = Interned::new((ustr("key"), ustr("value")));
+/// assert_eq!(pair.1, "value");
+/// ```
+pub struct Interned<T: 'static> {
+    entry: &'static Entry<T>,
+}
+
+struct Entry<T> {
+    hash: u64,
+    value: T,
+}
+
+// One shard of a pool, mapping hashes to the entries with that hash.
+type Shard<T> =
+    HashMap<u64, Vec<&'static Entry<T>>, BuildHasherDefault<IdentityHasher>>;
+
+struct Pool<T: 'static> {
+    shards: Box<[Mutex<Shard<T>>]>,
+}
+
+impl<T: Hash + Eq + Send + Sync + 'static> Pool<T> {
+    fn new() -> Pool<T> {
+        Pool {
+            shards: (0..NUM_BINS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    // Get the pool for `T`, creating it the first time.
+    fn get() -> &'static Pool<T> {
+        let id = TypeId::of::<T>();
+        if let Some(pool) = POOLS.read().get(&id) {
+            return pool
+                .as_any()
+                .downcast_ref()
+                .expect("pool has the wrong type");
+        }
+        let pool = *POOLS.write().entry(id).or_insert_with(
+            || -> &'static dyn AnyPool {
+                Box::leak(Box::new(Pool::<T>::new()))
+            },
+        );
+        pool.as_any()
+            .downcast_ref()
+            .expect("pool has the wrong type")
+    }
+
+    fn shard(&self, hash: u64) -> &Mutex<Shard<T>> {
+        // Use the top bits, like the string cache does.
+        &self.shards[(hash >> (u64::BITS - NUM_BINS.trailing_zeros())) as usize]
+    }
+
+    fn find<Q>(&self, value: &Q, hash: u64) -> Option<&'static Entry<T>>
+    where
+        T: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        self.shard(hash)
+            .lock()
+            .get(&hash)?
+            .iter()
+            .find(|e| e.value.borrow() == value)
+            .copied()
+    }
+
+    fn insert(&self, value: T, hash: u64) -> &'static Entry<T> {
+        let mut shard = self.shard(hash).lock();
+        let entries = shard.entry(hash).or_default();
+        if let Some(entry) = entries.iter().find(|e| e.value == value) {
+            return entry;
+        }
+        let entry = Box::leak(Box::new(Entry { hash, value }));
+        entries.push(entry);
+        entry
+    }
+
+    fn num_entries(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().values().map(Vec::len).sum::<usize>())
+            .sum()
+    }
+}
+
+// The parts of a pool that don't depend on its type.
+trait AnyPool: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn clear(&self);
+}
+
+impl<T: Hash + Eq + Send + Sync + 'static> AnyPool for Pool<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // Forget every value, so that they are interned again next time. The
+    // values themselves are leaked, so existing handles stay valid.
+    fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.lock().clear();
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    // The pool for each type that has been interned, leaked so that they
+    // live forever.
+    static ref POOLS: RwLock<HashMap<TypeId, &'static dyn AnyPool>> =
+        RwLock::new(HashMap::new());
+}
+
+// Forget every interned value of every type, for `_clear_cache()`, since
+// values can hold `Ustr`s that the cache is about to drop.
+pub(crate) fn clear_pools() {
+    for pool in POOLS.read().values() {
+        pool.clear();
+    }
+}
+
+// Hash with the same function as the global string cache, so that the
+// configured hash algorithm applies to interned values too.
+fn hash_value<Q: Hash + ?Sized>(value: &Q) -> u64 {
+    STRING_CACHE.hasher.hash_value(value)
+}
+
+impl<T: Hash + Eq + Send + Sync + 'static> Interned<T> {
+    /// Intern the given value, dropping it if an equal value is already in
+    /// the pool.
+    pub fn new(value: T) -> Interned<T> {
+        let hash = hash_value(&value);
+        Interned {
+            entry: Pool::get().insert(value, hash),
+        }
+    }
+
+    /// Get the handle for a value equal to `value`, but only if one has
+    /// already been interned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::Interned;
+    ///
+    /// let list = Interned::new(vec![1, 2, 3]);
+    /// assert_eq!(Interned::<Vec<i32>>::existing(&[1, 2, 3][..]), Some(list));
+    /// assert_eq!(Interned::<Vec<i32>>::existing(&[4][..]), None);
+    /// ```
+    pub fn existing<Q>(value: &Q) -> Option<Interned<T>>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = hash_value(value);
+        Pool::get()
+            .find(value, hash)
+            .map(|entry| Interned { entry })
+    }
+
+    /// The number of distinct values of type `T` that have been interned.
+    pub fn num_entries() -> usize {
+        Pool::<T>::get().num_entries()
+    }
+}
+
+impl<T: 'static> Interned<T> {
+    /// Get a reference to the interned value.
+    #[inline]
+    pub fn get(&self) -> &'static T {
+        &self.entry.value
+    }
+
+    /// Get the hash computed when the value was interned.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        self.entry.hash
+    }
+}
+
+impl<T: 'static> Clone for Interned<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: 'static> Copy for Interned<T> {}
+
+impl<T: 'static> PartialEq for Interned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.entry, other.entry)
+    }
+}
+
+impl<T: 'static> Eq for Interned<T> {}
+
+/// Defer to `T` for ordering.
+impl<T: Ord + 'static> Ord for Interned<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            return Ordering::Equal;
+        }
+        self.get().cmp(other.get())
+    }
+}
+
+impl<T: Ord + 'static> PartialOrd for Interned<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, as for `Ustr`.
+impl<T: 'static> Hash for Interned<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+impl<T: 'static> Deref for Interned<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.get()
+    }
+}
+
+impl<T: 'static> AsRef<T> for Interned<T> {
+    fn as_ref(&self) -> &T {
+        self.get()
+    }
+}
+
+impl<T: Hash + Eq + Send + Sync + 'static> From<T> for Interned<T> {
+    fn from(value: T) -> Interned<T> {
+        Interned::new(value)
+    }
+}
+
+impl<T: fmt::Debug + 'static> fmt::Debug for Interned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+impl<T: fmt::Display + 'static> fmt::Display for Interned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interned;
+    use crate::{ustr, InternedMap, Ustr};
+
+    #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+    enum Node {
+        Leaf(u32),
+        Pair(Interned<Node>, Interned<Node>),
+    }
+
+    #[test]
+    fn values() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        let a = Interned::new(Node::Leaf(1));
+        let b = Interned::new(Node::Leaf(2));
+        assert_ne!(a, b);
+        assert_eq!(a, Interned::new(Node::Leaf(1)));
+        assert!(a < b);
+        let pair = Interned::new(Node::Pair(a, b));
+        assert_eq!(pair, Interned::new(Node::Pair(a, b)));
+        assert_ne!(pair, Interned::new(Node::Pair(b, a)));
+        assert_eq!(Interned::existing(&Node::Leaf(1)), Some(a));
+        assert_eq!(Interned::<Node>::existing(&Node::Leaf(3)), None);
+        assert_eq!(format!("{:?}", pair), "Pair(Leaf(1), Leaf(2))");
+
+        // Every type has its own pool.
+        let t = Interned::new((ustr("a"), ustr("b")));
+        assert_eq!(t.0, "a");
+        assert_eq!(Interned::<(Ustr, Ustr)>::num_entries(), 1);
+        let s = Interned::new(String::from("hello"));
+        assert_eq!(Interned::<String>::existing("hello"), Some(s));
+        assert_eq!(Interned::<String>::num_entries(), 1);
+
+        let mut map = InternedMap::default();
+        for i in 0..1000 {
+            map.insert(Interned::new(i as u64), i);
+        }
+        for i in 0..1000 {
+            assert_eq!(map[&Interned::new(i as u64)], i);
+        }
+        assert_eq!(Interned::<u64>::num_entries(), 1000);
+    }
+
+    #[test]
+    fn threads() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    (0..1000)
+                        .map(|i: i32| Interned::new((i, -i)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let results: Vec<_> =
+            handles.into_iter().map(|h| h.join().unwrap()).collect();
+        for r in &results[1..] {
+            assert_eq!(r, &results[0]);
+        }
+        assert_eq!(Interned::<(i32, i32)>::num_entries(), 1000);
+    }
+}
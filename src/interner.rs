@@ -0,0 +1,495 @@
+use super::{
+    stringcache::{SECONDARY_ALLOC, SECONDARY_CAPACITY, SECONDARY_NUM_BINS},
+    Bins, CacheConfig, CacheStats, ConfigError, InternError, StringCacheEntry,
+};
+use std::{
+    cmp::Ordering,
+    ffi::CStr,
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    ops::Deref,
+    os::raw::c_char,
+    ptr::NonNull,
+    slice, str,
+};
+
+/// A string cache with its own storage, separate from the global cache.
+///
+/// This works exactly like the global cache behind [`Ustr`](crate::Ustr),
+/// but the strings it hands out borrow the `Interner`, and all of its memory
+/// is released when it is dropped. This is useful for giving e.g. each
+/// compilation session or each test its own pool of strings.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::Interner;
+///
+/// let interner = Interner::new();
+/// let s1 = interner.intern("the quick brown fox");
+/// let s2 = interner.intern("the quick brown fox");
+/// assert_eq!(s1, s2);
+/// assert_eq!(interner.len(), 1);
+/// assert_eq!(interner.get("the quick brown fox"), Some(s1));
+///
+/// // Nothing was added to the global cache.
+/// assert_eq!(ustr::existing_ustr("the quick brown fox"), None);
+/// ```
+pub struct Interner {
+    bins: Bins,
+}
+
+impl Interner {
+    /// Create a new, empty `Interner`.
+    ///
+    /// It starts out much smaller than the global cache, with 4096 slots
+    /// split between 4 bins and 64KiB of string storage, and grows as
+    /// needed. The other settings are the defaults of [`CacheConfig`]. Use
+    /// [`Interner::with_config`] to start bigger.
+    pub fn new() -> Interner {
+        Interner {
+            bins: Bins::new(&CacheConfig {
+                initial_capacity: SECONDARY_CAPACITY,
+                initial_alloc: SECONDARY_ALLOC,
+                num_bins: SECONDARY_NUM_BINS,
+                ..CacheConfig::default()
+            }),
+        }
+    }
+
+    /// Create a new, empty `Interner` with the given configuration.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::{configure, Interner};
+    ///
+    /// let interner = Interner::with_config(
+    ///     configure().initial_capacity(256).initial_alloc(4096).num_bins(1),
+    /// )
+    /// .unwrap();
+    /// assert_eq!(interner.intern("small"), "small");
+    /// ```
+    pub fn with_config(config: CacheConfig) -> Result<Interner, ConfigError> {
+        config.validate()?;
+        Ok(Interner {
+            bins: Bins::new(&config),
+        })
+    }
+
+    /// Intern the given string, returning a handle to the copy held by this
+    /// `Interner`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be stored. See [`Interner::try_intern`].
+    pub fn intern(&self, string: &str) -> InternedStr<'_> {
+        match self.try_intern(string) {
+            Ok(i) => i,
+            Err(e) => panic!(
+                "failed to intern string of length {}: {}",
+                string.len(),
+                e
+            ),
+        }
+    }
+
+    /// Intern the given string, returning an error if we run out of memory
+    /// or the configured memory budget would be exceeded.
+    pub fn try_intern(
+        &self,
+        string: &str,
+    ) -> Result<InternedStr<'_>, InternError> {
+        let hash = self.bins.hash(string);
+        let ptr = self.bins.insert(string, hash)?;
+        // SAFETY: insert does not give back a null pointer
+        Ok(unsafe { InternedStr::from_char_ptr(ptr) })
+    }
+
+    /// Get the handle for the given string but only if it has already been
+    /// interned.
+    pub fn get(&self, string: &str) -> Option<InternedStr<'_>> {
+        let hash = self.bins.hash(string);
+        self.bins
+            .get_existing(string, hash)
+            .map(|ptr| unsafe { InternedStr::from_char_ptr(ptr) })
+    }
+
+    /// Get the string with the given id, or `None` if this `Interner` doesn't
+    /// have a string with that id. This doesn't take any locks.
+    #[cfg(feature = "ids")]
+    pub fn from_id(&self, id: u32) -> Option<InternedStr<'_>> {
+        self.bins
+            .get_by_id(id)
+            .map(|ptr| unsafe { InternedStr::from_char_ptr(ptr) })
+    }
+
+    /// Returns the number of unique strings in this `Interner`.
+    pub fn len(&self) -> usize {
+        self.bins.num_entries()
+    }
+
+    /// Returns true if no strings have been interned.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the total amount of memory allocated and in use by this
+    /// `Interner` in bytes.
+    pub fn total_allocated(&self) -> usize {
+        self.bins.total_allocated()
+    }
+
+    /// Returns the total amount of memory reserved by this `Interner` in
+    /// bytes.
+    pub fn total_capacity(&self) -> usize {
+        self.bins.total_capacity()
+    }
+
+    /// Gather statistics about this `Interner`'s storage.
+    ///
+    /// See [`CacheStats`].
+    pub fn stats(&self) -> CacheStats {
+        self.bins.stats()
+    }
+
+    /// Return an iterator over all the strings in this `Interner`.
+    pub fn iter(&self) -> InternerIter<'_> {
+        InternerIter {
+            inner: self.bins.iter(),
+            _interner: PhantomData,
+        }
+    }
+}
+
+impl Default for Interner {
+    fn default() -> Self {
+        Interner::new()
+    }
+}
+
+impl fmt::Debug for Interner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interner")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<'a> IntoIterator for &'a Interner {
+    type Item = InternedStr<'a>;
+    type IntoIter = InternerIter<'a>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the strings in an [`Interner`].
+///
+/// Strings interned after the iterator was created might not show up.
+pub struct InternerIter<'a> {
+    inner: super::StringCacheIterator,
+    _interner: PhantomData<&'a Interner>,
+}
+
+impl<'a> Iterator for InternerIter<'a> {
+    type Item = InternedStr<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        // The iterator yields slices pointing at the chars of each entry.
+        self.inner
+            .next()
+            .map(|s| unsafe { InternedStr::from_char_ptr(s.as_ptr()) })
+    }
+}
+
+/// A handle to a string held by an [`Interner`].
+///
+/// This is the equivalent of a [`Ustr`](crate::Ustr) for an `Interner`:
+/// copying and comparing is just copying and comparing a pointer, and the
+/// hash is precomputed. It can't outlive the `Interner` it came from.
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct InternedStr<'a> {
+    char_ptr: NonNull<u8>,
+    _interner: PhantomData<&'a Interner>,
+}
+
+impl<'a> InternedStr<'a> {
+    // The pointer must point to the chars of a `StringCacheEntry` in an
+    // `Interner` that lives for 'a.
+    unsafe fn from_char_ptr(ptr: *const u8) -> InternedStr<'a> {
+        InternedStr {
+            char_ptr: NonNull::new_unchecked(ptr as *mut _),
+            _interner: PhantomData,
+        }
+    }
+
+    /// Get the interned string as a `str`.
+    pub fn as_str(&self) -> &'a str {
+        // This is safe for the same reasons as `Ustr::as_str()`, and the
+        // `Interner` keeps the memory alive for 'a.
+        unsafe {
+            str::from_utf8_unchecked(slice::from_raw_parts(
+                self.char_ptr.as_ptr(),
+                self.len(),
+            ))
+        }
+    }
+
+    /// Get the interned string as a C `char*`, including the null terminator.
+    pub fn as_char_ptr(&self) -> *const c_char {
+        self.char_ptr.as_ptr() as *const c_char
+    }
+
+    /// Get the interned string as a [`CStr`].
+    pub fn as_cstr(&self) -> &'a CStr {
+        unsafe {
+            CStr::from_bytes_with_nul_unchecked(slice::from_raw_parts(
+                self.char_ptr.as_ptr(),
+                self.len() + 1,
+            ))
+        }
+    }
+
+    #[inline]
+    fn as_string_cache_entry(&self) -> &'a StringCacheEntry {
+        // The allocator guarantees that the alignment is correct and that
+        // this pointer is non-null
+        unsafe { &*(self.char_ptr.as_ptr().cast::<StringCacheEntry>().sub(1)) }
+    }
+
+    /// Get the length (in bytes) of this string.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.as_string_cache_entry().len
+    }
+
+    /// Returns true if the length is zero.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the precomputed hash for this string.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        self.as_string_cache_entry().hash
+    }
+
+    /// Get the dense id of this string within its `Interner`, counting up from
+    /// 1 in insertion order. See [`UstrId`](crate::UstrId).
+    #[cfg(feature = "ids")]
+    #[inline]
+    pub fn id(&self) -> std::num::NonZeroU32 {
+        std::num::NonZeroU32::new(self.as_string_cache_entry().id)
+            .expect("interned strings always have an id")
+    }
+}
+
+// We're safe to impl these for the same reasons as `Ustr`. The borrow of the
+// `Interner` stops the strings from being freed while we're pointing at them.
+unsafe impl Send for InternedStr<'_> {}
+unsafe impl Sync for InternedStr<'_> {}
+
+impl PartialEq for InternedStr<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.char_ptr == other.char_ptr
+    }
+}
+
+impl Eq for InternedStr<'_> {}
+
+impl PartialEq<str> for InternedStr<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for InternedStr<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Ord for InternedStr<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialOrd for InternedStr<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Feed the precomputed hash to the `Hasher`, as for `Ustr`.
+impl Hash for InternedStr<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+impl Deref for InternedStr<'_> {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for InternedStr<'_> {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for InternedStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for InternedStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "i!({:?})", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interner;
+    use std::collections::HashSet;
+
+    #[test]
+    fn separate_pools() {
+        let a = Interner::new();
+        let b = Interner::new();
+
+        let a1 = a.intern("hello");
+        let b1 = b.intern("hello");
+        assert_eq!(a1, "hello");
+        assert_eq!(b1, "hello");
+        // Same string, different storage.
+        assert_ne!(a1.as_char_ptr(), b1.as_char_ptr());
+        assert_eq!(a1.precomputed_hash(), b1.precomputed_hash());
+
+        assert_eq!(a.get("world"), None);
+        let a2 = a.intern("world");
+        assert_eq!(a.get("world"), Some(a2));
+        assert_eq!(b.get("world"), None);
+
+        assert_eq!(a.len(), 2);
+        assert_eq!(b.len(), 1);
+
+        // Ids are per interner.
+        #[cfg(feature = "ids")]
+        {
+            assert_eq!(a1.id().get(), 1);
+            assert_eq!(a2.id().get(), 2);
+            assert_eq!(b1.id().get(), 1);
+            assert_eq!(a.from_id(2), Some(a2));
+            assert_eq!(b.from_id(2), None);
+        }
+    }
+
+    #[test]
+    // We have to disable miri here as it's far too slow unfortunately
+    #[cfg_attr(miri, ignore)]
+    fn blns() {
+        let blns = include_str!("../data/blns.txt");
+        let expected: HashSet<&str> = blns.split_whitespace().collect();
+
+        // Tiny initial sizes so that both the table and the allocators have
+        // to grow.
+        let interner = Interner::with_config(
+            crate::configure()
+                .initial_capacity(16)
+                .initial_alloc(256)
+                .num_bins(4),
+        )
+        .unwrap();
+        for s in blns.split_whitespace() {
+            let i = interner.intern(s);
+            assert_eq!(i, s);
+        }
+
+        assert_eq!(interner.len(), expected.len());
+        let found: HashSet<&str> =
+            interner.iter().map(|i| i.as_str()).collect();
+        assert_eq!(found, expected);
+        for s in &expected {
+            assert_eq!(interner.get(s).unwrap(), *s);
+        }
+    }
+
+    #[test]
+    fn budget() {
+        use crate::InternError;
+
+        let interner = Interner::with_config(
+            crate::configure()
+                .initial_capacity(16)
+                .initial_alloc(1024)
+                .num_bins(1)
+                .memory_budget(4096),
+        )
+        .unwrap();
+
+        let mut interned = Vec::new();
+        let err = loop {
+            let s = format!("string number {}", interned.len());
+            match interner.try_intern(&s) {
+                Ok(i) => interned.push(i),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, InternError::BudgetExceeded);
+        assert!(interner.total_capacity() <= 4096);
+
+        // The interner is still usable after the failure.
+        assert_eq!(interner.len(), interned.len());
+        for (n, i) in interned.iter().enumerate() {
+            let s = format!("string number {}", n);
+            assert_eq!(interner.try_intern(&s), Ok(*i));
+        }
+        assert_eq!(
+            interner.try_intern("one more string that doesn't fit"),
+            Err(InternError::BudgetExceeded)
+        );
+        assert_eq!(interner.get("one more string that doesn't fit"), None);
+    }
+
+    #[test]
+    fn entry_and_length_limits() {
+        use crate::InternError;
+
+        let interner = Interner::with_config(
+            crate::configure().max_entries(2).max_string_len(5),
+        )
+        .unwrap();
+        let a = interner.try_intern("alpha").unwrap();
+        assert_eq!(
+            interner.try_intern("bravo!"),
+            Err(InternError::StringTooLong)
+        );
+        interner.try_intern("bravo").unwrap();
+        assert_eq!(
+            interner.try_intern("delta"),
+            Err(InternError::TooManyEntries)
+        );
+        // Existing strings are still found.
+        assert_eq!(interner.try_intern("alpha"), Ok(a));
+        assert_eq!(interner.len(), 2);
+    }
+
+    #[test]
+    fn empty() {
+        let interner = Interner::new();
+        assert!(interner.is_empty());
+        assert_eq!(interner.iter().count(), 0);
+        let e = interner.intern("");
+        assert!(e.is_empty());
+        assert_eq!(e.as_cstr().to_bytes(), b"");
+        assert_eq!(interner.len(), 1);
+    }
+}
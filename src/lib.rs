@@ -87,6 +87,29 @@
 //! # }
 //! ```
 //!
+//! If you'd rather not share one global cache, an [`Interner`] gives you the
+//! same thing as a value you own. Its strings borrow it and its memory is
+//! released when it is dropped.
+//!
+//! ```
+//! let interner = ustr::Interner::new();
+//! let s1 = interner.intern("the quick brown fox");
+//! assert_eq!(s1, interner.intern("the quick brown fox"));
+//! ```
+//!
+//! By enabling the `"ids"` feature, every string also gets a dense `u32` id
+//! when it is interned, which is much smaller than a `Ustr` and can be turned
+//! back into one with `Ustr::from_id`. It's off by default as it adds 8
+//! bytes to every entry, plus a table mapping ids back to entries.
+//!
+//! ```
+//! # #[cfg(feature = "ids")] {
+//! use ustr::{ustr, Ustr};
+//! let u = ustr("give me an id");
+//! assert_eq!(Ustr::from_id(u.id().get()), Some(u));
+//! # }
+//! ```
+//!
 //! ## Why?
 //!
 //! It is common in certain types of applications to use strings as identifiers,
@@ -167,23 +190,66 @@ use std::{
     ops::Deref,
     os::raw::c_char,
     path::Path,
-    ptr::NonNull,
+    ptr::{self, NonNull},
     rc::Rc,
     slice, str,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicPtr, Ordering::AcqRel, Ordering::Acquire},
+        Arc,
+    },
 };
 
 mod hash;
 pub use hash::*;
 mod bumpalloc;
+mod config;
+pub use config::{configure, CacheConfig, ConfigError};
+#[cfg(feature = "thread-cache")]
+mod frontcache;
+#[cfg(feature = "thread-cache")]
+pub use frontcache::{thread_cache_hits, thread_cache_misses};
+#[cfg(feature = "ids")]
+mod id;
+#[cfg(feature = "ids")]
+use id::IdTable;
+pub mod image;
+#[cfg(feature = "ids")]
+pub use id::UstrId;
+use image::ImageRegion;
+mod interned;
+pub use interned::Interned;
+mod interner;
+pub use interner::{InternedStr, Interner, InternerIter};
+mod limits;
+use limits::Limits;
+pub use limits::{InternError, MaybeInterned};
+#[cfg(feature = "normalize")]
+mod normalize;
+#[cfg(feature = "normalize")]
+pub use normalize::{ustr_nfc, ustr_nfkc, UstrNfc};
+mod stats;
+pub use stats::{stats, BinStats, CacheStats};
 
+#[cfg(feature = "serde")]
+pub mod serde;
+pub mod snapshot;
 mod stringcache;
 pub use stringcache::*;
+mod ubytes;
+pub use ubytes::{existing_ubytes, ubytes, UBytes};
+mod upath;
+pub use upath::{existing_upath, upath, UPath};
+mod ustrci;
+pub use ustrci::{fold_case, UstrCi};
+mod ustrlist;
+pub use ustrlist::UstrList;
+mod ustrpath;
+pub use ustrpath::{UstrPath, UstrPathAncestors, UstrPathComponents};
 #[cfg(feature = "serde")]
 pub mod serialization;
 #[cfg(feature = "serde")]
-pub use serialization::DeserializedCache;
+pub use serialization::{ConsistentCache, DeserializedCache};
 
 /// A handle representing a string in the global string cache.
 ///
@@ -233,33 +299,96 @@ impl Ustr {
     /// assert_eq!(u1, u2);
     /// assert_eq!(ustr::num_entries(), 1);
     /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be interned, either because we ran out of
+    /// memory or because one of the configured limits would be exceeded. Use
+    /// [`Ustr::try_from`] if you need to handle that.
     pub fn from(string: &str) -> Ustr {
-        let hash = {
-            let mut hasher = ahash::AHasher::default();
-            hasher.write(string.as_bytes());
-            hasher.finish()
-        };
-        let mut sc = STRING_CACHE.0[whichbin(hash)].lock();
-        Ustr {
-            // SAFETY: sc.insert does not give back a null pointer
-            char_ptr: unsafe {
-                NonNull::new_unchecked(sc.insert(string, hash) as *mut _)
-            },
+        match Ustr::try_from(string) {
+            Ok(u) => u,
+            Err(e) => panic!(
+                "failed to intern string of length {}: {}",
+                string.len(),
+                e
+            ),
         }
     }
 
+    /// Create a new `Ustr` from the given `str`, returning an error rather
+    /// than panicking if it can't be stored.
+    ///
+    /// You can also use the [`try_ustr`] function.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::Ustr;
+    /// # unsafe { ustr::_clear_cache() };
+    ///
+    /// let u1 = Ustr::try_from("the quick brown fox").unwrap();
+    /// assert_eq!(u1, "the quick brown fox");
+    /// ```
+    pub fn try_from(string: &str) -> Result<Ustr, InternError> {
+        let hash = STRING_CACHE.hash(string);
+        #[cfg(feature = "thread-cache")]
+        let ptr = frontcache::get_or_insert(string, hash, || {
+            STRING_CACHE.insert(string, hash)
+        })?;
+        #[cfg(not(feature = "thread-cache"))]
+        let ptr = STRING_CACHE.insert(string, hash)?;
+        Ok(Ustr {
+            // SAFETY: insert does not give back a null pointer
+            char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        })
+    }
+
     pub fn from_existing(string: &str) -> Option<Ustr> {
-        let hash = {
-            let mut hasher = ahash::AHasher::default();
-            hasher.write(string.as_bytes());
-            hasher.finish()
-        };
-        let sc = STRING_CACHE.0[whichbin(hash)].lock();
-        sc.get_existing(string, hash).map(|ptr| Ustr {
+        let hash = STRING_CACHE.hash(string);
+        STRING_CACHE.get_existing(string, hash).map(|ptr| Ustr {
+            char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        })
+    }
+
+    /// Get the `Ustr` with the given id, or `None` if no string with that id
+    /// has been interned. This doesn't take any locks.
+    ///
+    /// See [`UstrId`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::{ustr, Ustr};
+    ///
+    /// let u = ustr("the quick brown fox");
+    /// assert_eq!(Ustr::from_id(u.id().get()), Some(u));
+    /// assert_eq!(Ustr::from_id(0), None);
+    /// ```
+    #[cfg(feature = "ids")]
+    #[inline]
+    pub fn from_id(id: u32) -> Option<Ustr> {
+        STRING_CACHE.get_by_id(id).map(|ptr| Ustr {
             char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
         })
     }
 
+    /// Get the dense id of this string. See [`UstrId`] for details.
+    #[cfg(feature = "ids")]
+    #[inline]
+    pub fn id(&self) -> UstrId {
+        let entry = self.as_string_cache_entry();
+        // Entries in cache images are never written to, so their ids are kept
+        // alongside the image instead.
+        let id = match entry.id {
+            0 => STRING_CACHE.image_slot(entry).map_or(0, |slot| {
+                slot.id.load(std::sync::atomic::Ordering::Relaxed)
+            }),
+            id => id,
+        };
+        UstrId::new(id).expect("interned strings always have an id")
+    }
+
     /// Get the cached `Ustr` as a `str`.
     ///
     /// # Examples
@@ -353,12 +482,137 @@ impl Ustr {
         self.len() == 0
     }
 
+    /// Get the number of chars in this string.
+    ///
+    /// This is the same as `self.chars().count()`, but it's counted once when
+    /// the string is interned rather than every time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::ustr;
+    ///
+    /// let u = ustr("naïve 🦀");
+    /// assert_eq!(u.len(), 11);
+    /// assert_eq!(u.char_count(), 7);
+    /// assert_eq!(u.utf16_len(), 8);
+    /// assert!(!u.is_ascii());
+    /// ```
+    #[cfg(feature = "char-counts")]
+    #[inline]
+    pub fn char_count(&self) -> usize {
+        self.as_string_cache_entry().char_count
+    }
+
+    /// Get the number of UTF-16 code units it takes to encode this string,
+    /// e.g. for positions in the Language Server Protocol.
+    ///
+    /// This is the same as `self.encode_utf16().count()`, but it's counted
+    /// once when the string is interned rather than every time.
+    #[cfg(feature = "char-counts")]
+    #[inline]
+    pub fn utf16_len(&self) -> usize {
+        self.as_string_cache_entry().utf16_len
+    }
+
+    /// Returns true if every char in this string is ASCII.
+    ///
+    /// This takes constant time, as it's the case exactly when the string has
+    /// as many chars as bytes.
+    #[cfg(feature = "char-counts")]
+    #[inline]
+    pub fn is_ascii(&self) -> bool {
+        self.char_count() == self.len()
+    }
+
     /// Get the precomputed hash for this string.
+    ///
+    /// The value depends on the cache's [`HashAlgorithm`]. Only
+    /// [`HashAlgorithm::Stable`] gives the same value on every machine and
+    /// with every version of this crate.
     #[inline]
     pub fn precomputed_hash(&self) -> u64 {
         self.as_string_cache_entry().hash
     }
 
+    /// Get the metadata word attached to this string.
+    ///
+    /// Every string in the cache has a `u64` that you can use to attach your
+    /// own data to it, such as the kind of keyword it is, without a separate
+    /// map. It starts at 0 and isn't saved in snapshots or cache images.
+    ///
+    /// The metadata is shared by every `Ustr` for the same string, and loads
+    /// and stores use acquire and release ordering.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::ustr;
+    ///
+    /// const KEYWORD: u64 = 1;
+    ///
+    /// ustr("fn").set_meta(KEYWORD);
+    /// assert_eq!(ustr("fn").meta(), KEYWORD);
+    /// assert_eq!(ustr("main").meta(), 0);
+    /// ```
+    #[cfg(feature = "meta")]
+    #[inline]
+    pub fn meta(&self) -> u64 {
+        self.meta_word().load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Set the metadata word attached to this string.
+    ///
+    /// See [`Ustr::meta`].
+    #[cfg(feature = "meta")]
+    #[inline]
+    pub fn set_meta(&self, meta: u64) {
+        self.meta_word()
+            .store(meta, std::sync::atomic::Ordering::Release)
+    }
+
+    /// Set the metadata word attached to this string to `new` if it is
+    /// `current`, as with [`AtomicU64::compare_exchange`].
+    ///
+    /// Returns the previous value, which is `Ok` if it was `current` and so
+    /// the metadata was replaced. This makes it easy to compute something
+    /// once and cache it, even if several threads race to do it.
+    ///
+    /// [`AtomicU64::compare_exchange`]: std::sync::atomic::AtomicU64::compare_exchange
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::ustr;
+    ///
+    /// let u = ustr("identifier");
+    /// assert_eq!(u.compare_exchange_meta(0, 42), Ok(0));
+    /// assert_eq!(u.compare_exchange_meta(0, 7), Err(42));
+    /// assert_eq!(u.meta(), 42);
+    /// ```
+    #[cfg(feature = "meta")]
+    #[inline]
+    pub fn compare_exchange_meta(
+        &self,
+        current: u64,
+        new: u64,
+    ) -> Result<u64, u64> {
+        self.meta_word()
+            .compare_exchange(current, new, AcqRel, Acquire)
+    }
+
+    // Entries in cache images are read-only, so their metadata lives in the
+    // image's slot instead.
+    #[cfg(feature = "meta")]
+    #[inline]
+    fn meta_word(&self) -> &'static std::sync::atomic::AtomicU64 {
+        let entry = self.as_string_cache_entry();
+        match STRING_CACHE.image_slot(entry) {
+            Some(slot) => &slot.meta,
+            None => unsafe { &*(&entry.meta as *const _) },
+        }
+    }
+
     /// Get an owned String copy of this string.
     pub fn to_owned(&self) -> String {
         self.as_str().to_owned()
@@ -449,24 +703,28 @@ impl PartialEq<Ustr> for &Box<str> {
     }
 }
 
+#[allow(clippy::op_ref, clippy::borrow_deref_ref)]
 impl PartialEq<Cow<'_, str>> for Ustr {
     fn eq(&self, other: &Cow<'_, str>) -> bool {
         self.as_str() == &*other
     }
 }
 
+#[allow(clippy::op_ref, clippy::borrow_deref_ref)]
 impl PartialEq<Ustr> for Cow<'_, str> {
     fn eq(&self, u: &Ustr) -> bool {
         &*self == u.as_str()
     }
 }
 
+#[allow(clippy::op_ref, clippy::borrow_deref_ref)]
 impl PartialEq<&Cow<'_, str>> for Ustr {
     fn eq(&self, other: &&Cow<'_, str>) -> bool {
         self.as_str() == &**other
     }
 }
 
+#[allow(clippy::op_ref, clippy::borrow_deref_ref)]
 impl PartialEq<Ustr> for &Cow<'_, str> {
     fn eq(&self, u: &Ustr) -> bool {
         &**self == u.as_str()
@@ -565,30 +823,35 @@ impl From<String> for Ustr {
     }
 }
 
+#[allow(clippy::explicit_auto_deref)]
 impl From<&String> for Ustr {
     fn from(s: &String) -> Ustr {
         Ustr::from(&**s)
     }
 }
 
+#[allow(clippy::explicit_auto_deref)]
 impl From<Box<str>> for Ustr {
     fn from(s: Box<str>) -> Ustr {
         Ustr::from(&*s)
     }
 }
 
+#[allow(clippy::explicit_auto_deref)]
 impl From<Rc<str>> for Ustr {
     fn from(s: Rc<str>) -> Ustr {
         Ustr::from(&*s)
     }
 }
 
+#[allow(clippy::explicit_auto_deref)]
 impl From<Arc<str>> for Ustr {
     fn from(s: Arc<str>) -> Ustr {
         Ustr::from(&*s)
     }
 }
 
+#[allow(clippy::explicit_auto_deref)]
 impl From<Cow<'_, str>> for Ustr {
     fn from(s: Cow<'_, str>) -> Ustr {
         Ustr::from(&*s)
@@ -639,35 +902,27 @@ impl Hash for Ustr {
 /// DO NOT CALL THIS.
 #[doc(hidden)]
 pub unsafe fn _clear_cache() {
-    for m in STRING_CACHE.0.iter() {
-        m.lock().clear();
-    }
+    STRING_CACHE.clear();
+    let mut capacity = STRING_CACHE.total_capacity();
+    for bins in OTHER_CACHES.lock().iter() {
+        bins.clear();
+        capacity += bins.total_capacity();
+    }
+    STRING_CACHE.limits.reset(capacity);
+    interned::clear_pools();
+    #[cfg(feature = "thread-cache")]
+    frontcache::invalidate();
 }
 
 /// Returns the total amount of memory allocated and in use by the cache in
 /// bytes.
 pub fn total_allocated() -> usize {
-    STRING_CACHE
-        .0
-        .iter()
-        .map(|sc| {
-            let t = sc.lock().total_allocated();
-
-            t
-        })
-        .sum()
+    STRING_CACHE.total_allocated()
 }
 
 /// Returns the total amount of memory reserved by the cache in bytes.
 pub fn total_capacity() -> usize {
-    STRING_CACHE
-        .0
-        .iter()
-        .map(|sc| {
-            let t = sc.lock().total_capacity();
-            t
-        })
-        .sum()
+    STRING_CACHE.total_capacity()
 }
 
 /// Create a new `Ustr` from the given `str`.
@@ -688,9 +943,145 @@ pub fn ustr(s: &str) -> Ustr {
     Ustr::from(s)
 }
 
+/// Intern all the given strings, returning them in the same order.
+///
+/// This is quicker than calling [`ustr()`] for each one when there are lots
+/// of strings, since it takes the lock for each of the cache's bins only once
+/// rather than once per string.
+///
+/// # Panics
+///
+/// Panics if a string can't be stored, like [`ustr()`].
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{intern_many, ustr};
+///
+/// let symbols = intern_many(["main", "argc", "argv", "main"]);
+/// assert_eq!(symbols, [ustr("main"), ustr("argc"), ustr("argv"), ustr("main")]);
+/// ```
+pub fn intern_many<'a, I>(strings: I) -> Vec<Ustr>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    try_intern_many(strings)
+        .unwrap_or_else(|e| panic!("failed to intern strings: {}", e))
+}
+
+/// Intern all the given strings, returning them in the same order, or an
+/// error if any of them can't be stored.
+///
+/// Strings before the one that couldn't be stored might still have been
+/// added to the cache. See [`intern_many()`].
+pub fn try_intern_many<'a, I>(strings: I) -> Result<Vec<Ustr>, InternError>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let strings: Vec<&str> = strings.into_iter().collect();
+    let mut ptrs = vec![std::ptr::null(); strings.len()];
+    STRING_CACHE.insert_many(&strings, |i, ptr| ptrs[i] = ptr)?;
+    Ok(ptrs
+        .into_iter()
+        .map(|ptr| Ustr {
+            // SAFETY: every slot was filled in with a pointer from insert
+            char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        })
+        .collect())
+}
+
+/// Intern all the strings in `strings`, writing them to the same positions
+/// in `out`.
+///
+/// This is like [`intern_many()`], but reuses the memory of an existing
+/// slice.
+///
+/// # Panics
+///
+/// Panics if the slices are different lengths, or if a string can't be
+/// stored.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{intern_many_into, ustr, Ustr};
+///
+/// let mut out = [Ustr::default(); 2];
+/// intern_many_into(&["x", "y"], &mut out);
+/// assert_eq!(out, [ustr("x"), ustr("y")]);
+/// ```
+pub fn intern_many_into(strings: &[&str], out: &mut [Ustr]) {
+    assert_eq!(
+        strings.len(),
+        out.len(),
+        "intern_many_into() needs an output slot for every string"
+    );
+    STRING_CACHE
+        .insert_many(strings, |i, ptr| {
+            out[i] = Ustr {
+                // SAFETY: insert does not give back a null pointer
+                char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+            }
+        })
+        .unwrap_or_else(|e| panic!("failed to intern strings: {}", e))
+}
+
+/// Create a new `Ustr` from the given `str`, returning an error if it can't be
+/// stored.
+///
+/// This is useful when interning untrusted input with a memory budget set
+/// through [`CacheConfig::memory_budget`].
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{try_ustr, InternError};
+///
+/// fn intern_header(name: &str) -> Result<ustr::Ustr, InternError> {
+///     try_ustr(name)
+/// }
+/// assert_eq!(intern_header("content-type").unwrap(), "content-type");
+/// ```
+#[inline]
+pub fn try_ustr(s: &str) -> Result<Ustr, InternError> {
+    Ustr::try_from(s)
+}
+
+/// Intern the given `str` if it fits within the cache's limits, otherwise
+/// return an owned copy of it.
+///
+/// Where [`try_ustr`] rejects a string that would go over one of the limits
+/// set with [`configure()`], this falls back to a copy that isn't in the
+/// cache, so the limits only bound the memory the cache itself uses. Running
+/// out of memory is still an error.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{configure, ustr_or_owned};
+///
+/// configure().max_string_len(16).init().unwrap();
+///
+/// let short = ustr_or_owned("accept").unwrap();
+/// assert!(short.is_interned());
+/// let long = ustr_or_owned("x-some-very-long-custom-header").unwrap();
+/// assert!(!long.is_interned());
+/// assert_eq!(long, "x-some-very-long-custom-header");
+/// ```
+pub fn ustr_or_owned(s: &str) -> Result<MaybeInterned, InternError> {
+    match Ustr::try_from(s) {
+        Ok(u) => Ok(MaybeInterned::Interned(u)),
+        Err(e) if e.is_limit() => Ok(MaybeInterned::Owned(s.into())),
+        Err(e) => Err(e),
+    }
+}
+
 /// Create a new `Ustr` from the given `str` but only if it already exists in
 /// the string cache.
 ///
+/// This never takes a lock, so it doesn't have to wait for other threads that
+/// are interning strings.
+///
 /// # Examples
 ///
 /// ```
@@ -740,25 +1131,15 @@ pub fn cache() -> &'static Bins {
 /// assert_eq!(ustr::num_entries(), 2);
 /// ```
 pub fn num_entries() -> usize {
-    STRING_CACHE
-        .0
-        .iter()
-        .map(|sc| {
-            let t = sc.lock().num_entries();
-            t
-        })
-        .sum()
+    STRING_CACHE.num_entries()
 }
 
 #[doc(hidden)]
 pub fn num_entries_per_bin() -> Vec<usize> {
     STRING_CACHE
-        .0
+        .bins
         .iter()
-        .map(|sc| {
-            let t = sc.lock().num_entries();
-            t
-        })
+        .map(|sc| sc.lock().num_entries())
         .collect::<Vec<_>>()
 }
 
@@ -775,51 +1156,368 @@ pub fn num_entries_per_bin() -> Vec<usize> {
 /// destroy the strings, they remain valid, meaning it's safe to iterate over
 /// them, the list just might not be completely up to date.
 pub fn string_cache_iter() -> StringCacheIterator {
-    let mut allocs = Vec::new();
-    for m in STRING_CACHE.0.iter() {
-        let sc = m.lock();
-        // the start of the allocator's data is actually the ptr, start() just
-        // points to the beginning of the allocated region. The first bytes will
-        // be uninitialized since we're bumping down
-        for a in &sc.old_allocs {
-            allocs.push((a.ptr(), a.end()));
+    STRING_CACHE.iter()
+}
+
+/// The type used for the global string cache.
+///
+/// This is exposed to allow e.g. serialization of the data returned by the
+/// [`cache()`] function.
+pub struct Bins {
+    pub(crate) bins: Box<[Bin]>,
+    // Shift for top bits to determine bin a hash falls into
+    top_shift: u32,
+    // Limits shared by all the bins, and by all the global caches.
+    pub(crate) limits: Arc<Limits>,
+    // Maximum length of a single string in bytes.
+    max_len: usize,
+    // Maps ids to entries for all the bins.
+    #[cfg(feature = "ids")]
+    pub(crate) ids: IdTable,
+    // Cache images whose entries have been linked into the bins, or null if
+    // there aren't any. This is read without a lock: registering an image
+    // publishes a new list and leaks the old one, which a reader may still
+    // be looking at. There are only ever a few images, and they are leaked
+    // anyway.
+    images: AtomicPtr<Vec<&'static ImageRegion>>,
+    // The hash function for the strings.
+    pub(crate) hasher: StrHasher,
+}
+
+impl Bins {
+    pub(crate) fn new(config: &CacheConfig) -> Bins {
+        let limits = Arc::new(Limits::new(config));
+        // The initial storage counts towards the budget. The config has
+        // already been validated so we know it fits.
+        limits.reset(config.bin_alloc() * config.num_bins);
+        Bins::with_limits(config, limits, config.max_string_len)
+    }
+
+    // Create bins that share `limits` with other caches. The initial storage
+    // has to be counted in `limits` by the caller.
+    fn with_limits(
+        config: &CacheConfig,
+        limits: Arc<Limits>,
+        max_len: Option<usize>,
+    ) -> Bins {
+        let bins: Box<[Bin]> =
+            (0..config.num_bins).map(|_| Bin::new(config)).collect();
+        Bins {
+            bins,
+            top_shift: u64::BITS - config.num_bins.trailing_zeros(),
+            limits,
+            max_len: max_len.unwrap_or(usize::MAX),
+            #[cfg(feature = "ids")]
+            ids: IdTable::new(),
+            images: AtomicPtr::new(ptr::null_mut()),
+            hasher: StrHasher::new(config.hash_algorithm),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn hash(&self, string: &str) -> u64 {
+        #[cfg(test)]
+        HASH_CALLS.with(|calls| calls.set(calls.get() + 1));
+        self.hasher.hash(string.as_bytes())
+    }
+
+    pub(crate) fn check_len(&self, len: usize) -> Result<(), InternError> {
+        if len > self.max_len {
+            Err(InternError::StringTooLong)
+        } else {
+            Ok(())
+        }
+    }
+
+    #[inline]
+    pub(crate) fn hash_bytes(&self, bytes: &[u8]) -> u64 {
+        self.hasher.hash(bytes)
+    }
+
+    // Use the top bits of the hash to choose a bin
+    #[inline]
+    pub(crate) fn whichbin(&self, hash: u64) -> usize {
+        // With a single bin the shift is the full width of the hash.
+        hash.checked_shr(self.top_shift).unwrap_or(0) as usize
+    }
+
+    #[inline]
+    pub(crate) fn bin(&self, hash: u64) -> &Bin {
+        &self.bins[self.whichbin(hash)]
+    }
+
+    // Insert the given string with its given hash, returning a pointer to the
+    // interned chars.
+    pub(crate) fn insert(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Result<*const u8, InternError> {
+        self.insert_bytes(string.as_bytes(), hash)
+    }
+
+    // Insert a byte string. The caches that `Ustr`s point into must only
+    // ever have valid UTF-8 inserted.
+    pub(crate) fn insert_bytes(
+        &self,
+        bytes: &[u8],
+        hash: u64,
+    ) -> Result<*const u8, InternError> {
+        let bin = self.bin(hash);
+        // Most strings are already in the cache, and we can find those
+        // without taking the lock.
+        if let Some(ptr) = bin.get_existing(bytes, hash) {
+            return Ok(ptr);
+        }
+        bin.lock().insert(bytes, hash, self)
+    }
+
+    // Look up a string without taking any locks.
+    pub(crate) fn get_existing(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Option<*const u8> {
+        self.get_existing_bytes(string.as_bytes(), hash)
+    }
+
+    // Look up a byte string without taking any locks.
+    pub(crate) fn get_existing_bytes(
+        &self,
+        bytes: &[u8],
+        hash: u64,
+    ) -> Option<*const u8> {
+        self.bin(hash).get_existing(bytes, hash)
+    }
+
+    // Insert all the given strings, locking each bin only once, and call `f`
+    // with the index of each string and a pointer to its interned chars. The
+    // strings are inserted grouped by bin, so `f` isn't called in order.
+    //
+    // If this returns an error, the strings inserted before it failed stay in
+    // the cache.
+    pub(crate) fn insert_many<F>(
+        &self,
+        strings: &[&str],
+        f: F,
+    ) -> Result<(), InternError>
+    where
+        F: FnMut(usize, *const u8),
+    {
+        let hashes: Vec<u64> = strings.iter().map(|s| self.hash(s)).collect();
+        self.insert_many_hashed(strings, &hashes, f)
+    }
+
+    // As `insert_many()`, for strings whose hashes have already been
+    // computed.
+    pub(crate) fn insert_many_hashed<F>(
+        &self,
+        strings: &[&str],
+        hashes: &[u64],
+        mut f: F,
+    ) -> Result<(), InternError>
+    where
+        F: FnMut(usize, *const u8),
+    {
+        // Counting sort the indices of the strings by bin.
+        let mut starts = vec![0; self.bins.len() + 1];
+        for hash in hashes {
+            starts[self.whichbin(*hash) + 1] += 1;
+        }
+        for b in 0..self.bins.len() {
+            starts[b + 1] += starts[b];
+        }
+        let mut next = starts.clone();
+        let mut order = vec![0; strings.len()];
+        for (i, hash) in hashes.iter().enumerate() {
+            let b = self.whichbin(*hash);
+            order[next[b]] = i;
+            next[b] += 1;
+        }
+
+        for (b, m) in self.bins.iter().enumerate() {
+            let indices = &order[starts[b]..starts[b + 1]];
+            if indices.is_empty() {
+                continue;
+            }
+            let mut sc = m.lock();
+            for &i in indices {
+                let ptr = sc.insert(strings[i].as_bytes(), hashes[i], self)?;
+                f(i, ptr);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn num_entries(&self) -> usize {
+        self.bins.iter().map(|sc| sc.lock().num_entries()).sum()
+    }
+
+    pub(crate) fn total_allocated(&self) -> usize {
+        self.bins.iter().map(|sc| sc.lock().total_allocated()).sum()
+    }
+
+    pub(crate) fn total_capacity(&self) -> usize {
+        self.bins.iter().map(|sc| sc.lock().total_capacity()).sum()
+    }
+
+    // Iterator over the strings in every bin.
+    //
+    // The bins are locked one at a time, so if other threads are interning
+    // strings the iterator might include a string from one bin without one
+    // that was interned before it in another bin.
+    pub(crate) fn iter(&self) -> StringCacheIterator {
+        let mut allocs = Vec::new();
+        for m in self.bins.iter() {
+            push_allocs(&m.lock(), &mut allocs);
+        }
+        self.iter_allocs(allocs)
+    }
+
+    // Iterator over the strings that were in the cache at a single point in
+    // time. This holds the locks for all the bins at once while finding the
+    // allocators, so it blocks interning new strings for a little longer
+    // than `iter()`.
+    pub(crate) fn iter_consistent(&self) -> StringCacheIterator {
+        let mut allocs = Vec::new();
+        let bins: Vec<_> = self.bins.iter().map(|m| m.lock()).collect();
+        for sc in &bins {
+            push_allocs(sc, &mut allocs);
+        }
+        let iter = self.iter_allocs(allocs);
+        drop(bins);
+        iter
+    }
+
+    fn iter_allocs(
+        &self,
+        allocs: Vec<(*const u8, *const u8)>,
+    ) -> StringCacheIterator {
+        let current_ptr =
+            allocs.first().map(|s| s.0).unwrap_or_else(std::ptr::null);
+
+        StringCacheIterator {
+            allocs,
+            current_alloc: 0,
+            current_ptr,
+            images: self.images().to_vec(),
+            current_image: 0,
+            current_entry: 0,
+        }
+    }
+
+    fn images(&self) -> &[&'static ImageRegion] {
+        let images = self.images.load(Acquire);
+        if images.is_null() {
+            &[]
+        } else {
+            // Published lists are only freed by `clear` and `drop`.
+            unsafe { &*images }
+        }
+    }
+
+    pub(crate) fn add_image(&self, image: &'static ImageRegion) {
+        let mut current = self.images.load(Acquire);
+        loop {
+            let mut images = if current.is_null() {
+                Vec::new()
+            } else {
+                unsafe { (*current).clone() }
+            };
+            images.push(image);
+            let new = Box::into_raw(Box::new(images));
+            match self.images.compare_exchange(current, new, AcqRel, Acquire) {
+                Ok(_) => return,
+                Err(actual) => {
+                    // Nobody else has seen the list, so it's safe to free.
+                    drop(unsafe { Box::from_raw(new) });
+                    current = actual;
+                }
+            }
+        }
+    }
+
+    // Get the slot for an entry if it lives in a registered image rather
+    // than in the bins' own storage.
+    #[cfg(any(feature = "ids", feature = "meta"))]
+    #[inline]
+    pub(crate) fn image_slot(
+        &self,
+        entry: &StringCacheEntry,
+    ) -> Option<&'static image::ImageSlot> {
+        self.images()
+            .iter()
+            .find(|image| image.contains(entry))
+            .map(|image| image.slot(entry))
+    }
+
+    // Only for clearing the global cache between tests and benchmark runs.
+    // The limits have to be reset separately, as they may be shared.
+    pub(crate) unsafe fn clear(&self) {
+        for m in self.bins.iter() {
+            m.lock().clear();
         }
-        let ptr = sc.alloc.ptr();
-        let end = sc.alloc.end();
-        if ptr != end {
-            allocs.push((sc.alloc.ptr(), sc.alloc.end()));
+        #[cfg(feature = "ids")]
+        self.ids.clear();
+        self.free_images();
+    }
+
+    // Only for when nothing can be looking at the list of images.
+    unsafe fn free_images(&self) {
+        let images = self.images.swap(ptr::null_mut(), AcqRel);
+        if !images.is_null() {
+            drop(Box::from_raw(images));
         }
     }
 
-    let current_ptr =
-        allocs.first().map(|s| s.0).unwrap_or_else(std::ptr::null);
+    // Get the chars of the entry with the given id.
+    #[cfg(feature = "ids")]
+    #[inline]
+    pub(crate) fn get_by_id(&self, id: u32) -> Option<*const u8> {
+        self.ids
+            .get(id)
+            .map(|entry| unsafe { entry.as_ref() }.char_ptr())
+    }
+}
 
-    StringCacheIterator {
-        allocs,
-        current_alloc: 0,
-        current_ptr,
+impl Drop for Bins {
+    fn drop(&mut self) {
+        unsafe { self.free_images() };
     }
 }
 
-/// The type used for the global string cache.
-///
-/// This is exposed to allow e.g. serialization of the data returned by the
-/// [`cache()`] function.
-#[repr(transparent)]
-pub struct Bins(pub(crate) [Mutex<StringCache>; NUM_BINS]);
+// Add the regions of a bin's allocators that hold strings to `allocs`.
+fn push_allocs(sc: &StringCache, allocs: &mut Vec<(*const u8, *const u8)>) {
+    // the start of the allocator's data is actually the ptr, start() just
+    // points to the beginning of the allocated region. The first bytes will
+    // be uninitialized since we're bumping down
+    for a in &sc.old_allocs {
+        allocs.push((a.ptr(), a.end()));
+    }
+    let ptr = sc.alloc.ptr();
+    let end = sc.alloc.end();
+    if ptr != end {
+        allocs.push((ptr, end));
+    }
+}
 
 #[cfg(test)]
 lazy_static::lazy_static! {
     static ref TEST_LOCK: Mutex<()> = Mutex::new(());
 }
 
+#[cfg(test)]
+thread_local! {
+    // The number of strings `Bins::hash` has hashed on this thread.
+    static HASH_CALLS: std::cell::Cell<usize> =
+        const { std::cell::Cell::new(0) };
+}
+
 #[cfg(test)]
 mod tests {
     use super::TEST_LOCK;
-    use lazy_static::lazy_static;
     use std::ffi::OsStr;
     use std::path::Path;
-    use std::sync::Mutex;
 
     #[test]
     fn it_works() {
@@ -1040,6 +1738,82 @@ mod tests {
         assert_eq!(diff.len(), 0);
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialization_error() {
+        let _t = TEST_LOCK.lock();
+        use super::ustr as u;
+
+        unsafe { super::_clear_cache() };
+        u("first");
+        u("second");
+
+        // A writer that fails once the first string has been written.
+        struct Full(usize);
+        impl std::io::Write for Full {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.0 < buf.len() {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                self.0 -= buf.len();
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        assert!(serde_json::to_writer(Full(10), super::cache()).is_err());
+        assert!(
+            serde_json::to_writer(Full(10), &super::cache().consistent())
+                .is_err()
+        );
+        assert!(serde_json::to_writer(Full(100), super::cache()).is_ok());
+    }
+
+    #[cfg(all(feature = "serde", not(miri)))]
+    #[test]
+    fn serialization_consistent() {
+        let _t = TEST_LOCK.lock();
+        use super::ustr as u;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        unsafe { super::_clear_cache() };
+
+        // Each thread interns its strings in order, so in a consistent
+        // snapshot the strings from each thread are always a prefix of them.
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let done = &done;
+                scope.spawn(move || {
+                    let mut n = 0;
+                    while !done.load(Ordering::Relaxed) && n < 100_000 {
+                        u(&format!("{}-{}", t, n));
+                        n += 1;
+                    }
+                });
+            }
+
+            for _ in 0..10 {
+                let json = serde_json::to_string(&super::cache().consistent())
+                    .unwrap();
+                let strings: Vec<String> = serde_json::from_str(&json).unwrap();
+                let mut counts = [0usize; 4];
+                let mut max = [0usize; 4];
+                for s in &strings {
+                    let (t, n) = s.split_once('-').unwrap();
+                    let t: usize = t.parse().unwrap();
+                    let n: usize = n.parse().unwrap();
+                    counts[t] += 1;
+                    max[t] = max[t].max(n + 1);
+                }
+                assert_eq!(counts, max);
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
     #[cfg(all(feature = "serde", not(miri)))]
     #[test]
     fn serialization_ustr() {
@@ -1055,6 +1829,39 @@ mod tests {
         assert_eq!(u_hello, me_hello);
     }
 
+    #[test]
+    fn many() {
+        let _t = TEST_LOCK.lock();
+        use super::{
+            intern_many, intern_many_into, try_intern_many, ustr, Ustr,
+        };
+        unsafe { super::_clear_cache() };
+
+        let blns = include_str!("../data/blns.txt");
+        let strings: Vec<&str> =
+            blns.split_whitespace().cycle().take(10_000).collect();
+        let us = intern_many(strings.iter().copied());
+        assert_eq!(us.len(), strings.len());
+        for (u, s) in us.iter().zip(&strings) {
+            assert_eq!(u.as_str(), *s);
+            assert_eq!(*u, ustr(s));
+        }
+        assert_eq!(
+            super::num_entries(),
+            strings
+                .iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len()
+        );
+
+        let mut out = vec![Ustr::default(); strings.len()];
+        intern_many_into(&strings, &mut out);
+        assert_eq!(out, us);
+
+        assert!(intern_many(std::iter::empty()).is_empty());
+        assert_eq!(try_intern_many(["x", "y"]).unwrap(), ["x", "y"]);
+    }
+
     #[test]
     fn partial_ord() {
         let _t = TEST_LOCK.lock();
@@ -1102,6 +1909,124 @@ mod tests {
         assert_eq!(Some(s1), s2);
     }
 
+    #[test]
+    fn shared_limits() {
+        use super::{configure, Bins, InternError};
+
+        let config = configure().max_entries(3).max_string_len(4);
+        let strings = Bins::new(&config);
+        let records = Bins::with_limits(&config, strings.limits.clone(), None);
+
+        let insert = |bins: &Bins, s: &str| bins.insert(s, bins.hash(s));
+        assert!(insert(&strings, "a").is_ok());
+        assert_eq!(insert(&strings, "longer"), Err(InternError::StringTooLong));
+        // The length limit is only for strings.
+        assert!(insert(&records, "longer").is_ok());
+        assert!(insert(&records, "b").is_ok());
+        // But the entry limit covers both.
+        assert_eq!(insert(&strings, "c"), Err(InternError::TooManyEntries));
+        assert_eq!(insert(&records, "c"), Err(InternError::TooManyEntries));
+        assert!(insert(&strings, "a").is_ok());
+    }
+
+    #[cfg(feature = "ids")]
+    #[test]
+    fn ids() {
+        let _t = TEST_LOCK.lock();
+        use super::{ustr, Ustr};
+
+        unsafe { super::_clear_cache() };
+        let strings: Vec<_> = (0..1000).map(|n| ustr(&n.to_string())).collect();
+        for (n, u) in strings.iter().enumerate() {
+            assert_eq!(u.id().get(), n as u32 + 1);
+            assert_eq!(Ustr::from_id(n as u32 + 1), Some(*u));
+            assert_eq!(ustr(&n.to_string()).id(), u.id());
+        }
+        assert_eq!(Ustr::from_id(1001), None);
+
+        // Clearing the cache starts the ids again.
+        unsafe { super::_clear_cache() };
+        assert_eq!(Ustr::from_id(1), None);
+        assert_eq!(ustr("first").id().get(), 1);
+    }
+
+    #[cfg(feature = "meta")]
+    #[test]
+    fn meta() {
+        let _t = TEST_LOCK.lock();
+        use super::ustr;
+
+        unsafe { super::_clear_cache() };
+        let kw = ustr("while");
+        assert_eq!(kw.meta(), 0);
+        kw.set_meta(3);
+        assert_eq!(ustr("while").meta(), 3);
+        assert_eq!(ustr("whilst").meta(), 0);
+
+        // Only one of the threads gets to set it.
+        let winners: usize = std::thread::scope(|scope| {
+            let handles: Vec<_> = (1..=8)
+                .map(|t| {
+                    scope.spawn(move || {
+                        ustr("lazy").compare_exchange_meta(0, t).is_ok()
+                            as usize
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(winners, 1);
+        assert_ne!(ustr("lazy").meta(), 0);
+
+        // Metadata isn't saved, and a fresh cache starts from 0 again.
+        let mut bytes = Vec::new();
+        crate::snapshot::write(&mut bytes).unwrap();
+        unsafe { super::_clear_cache() };
+        crate::snapshot::load(&mut bytes.as_slice()).unwrap();
+        assert_eq!(ustr("while").meta(), 0);
+    }
+
+    #[cfg(feature = "char-counts")]
+    #[test]
+    fn char_counts() {
+        let _t = TEST_LOCK.lock();
+        use super::ustr;
+
+        unsafe { super::_clear_cache() };
+        let strings = [
+            "",
+            "ascii",
+            "héllo wörld",
+            "日本語",
+            "🦀 and 🐍",
+            "a\0b",
+            "\u{7f}\u{80}\u{7ff}\u{800}\u{ffff}\u{10000}\u{10ffff}",
+        ];
+        for s in strings {
+            let u = ustr(s);
+            assert_eq!(u.char_count(), s.chars().count(), "{:?}", s);
+            assert_eq!(u.utf16_len(), s.encode_utf16().count(), "{:?}", s);
+            assert_eq!(u.is_ascii(), s.is_ascii(), "{:?}", s);
+        }
+
+        // Strings from images keep the counts they were written with.
+        let mut bytes = Vec::new();
+        crate::image::write(&mut bytes).unwrap();
+        unsafe { super::_clear_cache() };
+        let mut words = vec![0u64; bytes.len().div_ceil(8)];
+        let image = unsafe {
+            std::slice::from_raw_parts_mut(
+                words.as_mut_ptr() as *mut u8,
+                bytes.len(),
+            )
+        };
+        image.copy_from_slice(&bytes);
+        std::mem::forget(words);
+        crate::image::register(image).unwrap();
+        let u = ustr("🦀 and 🐍");
+        assert_eq!((u.char_count(), u.utf16_len()), (7, 9));
+    }
+
     #[test]
     fn test_empty_cache() {
         unsafe { super::_clear_cache() };
@@ -1140,37 +2065,29 @@ mod tests {
 }
 
 lazy_static::lazy_static! {
-    static ref STRING_CACHE: Bins = {
-        use std::mem::{self, MaybeUninit};
-        // This deeply unsafe feeling dance allows us to initialize an array of
-        // arbitrary size and will have to tide us over until const generics
-        // land. See:
-        // https://doc.rust-lang.org/beta/std/mem/union.MaybeUninit.html#initializing-an-array-element-by-element
-
-        // Create an uninitialized array of `MaybeUninit`. The `assume_init` is
-        // safe because the type we are claiming to have initialized here is a
-        // bunch of `MaybeUninit`s, which do not require initialization.
-        let mut bins: [MaybeUninit<Mutex<StringCache>>; NUM_BINS] = unsafe {
-            MaybeUninit::uninit().assume_init()
-        };
-
-        // Dropping a `MaybeUninit` does nothing. Thus using raw pointer
-        // assignment instead of `ptr::write` does not cause the old
-        // uninitialized value to be dropped. Also if there is a panic during
-        // this loop, we have a memory leak, but there is no memory safety
-        // issue.
-        for bin in &mut bins[..] {
-            *bin = MaybeUninit::new(Mutex::new(StringCache::default()));
-        }
-
-        // Everything is initialized. Transmute the array to the
-        // initialized type.
-        unsafe { mem::transmute::<_, Bins>(bins) }
-    };
+    static ref STRING_CACHE: Bins = Bins::new(&config::global_config());
+    // The other global caches that have been created, for `_clear_cache()`.
+    static ref OTHER_CACHES: Mutex<Vec<&'static Bins>> = Mutex::new(Vec::new());
 }
 
-// Use the top bits of the hash to choose a bin
-#[inline]
-fn whichbin(hash: u64) -> usize {
-    ((hash >> TOP_SHIFT as u64) % NUM_BINS as u64) as usize
+// Create another global cache, e.g. for byte strings. It shares the memory
+// budget and entry limit of the string cache, but starts much smaller. Only
+// caches of strings are held to the maximum string length, rather than ones
+// of records that just happen to be stored the same way.
+pub(crate) fn new_global_cache(strings: bool) -> &'static Bins {
+    let config = CacheConfig {
+        initial_capacity: SECONDARY_CAPACITY,
+        initial_alloc: SECONDARY_ALLOC,
+        num_bins: SECONDARY_NUM_BINS,
+        ..config::global_config()
+    };
+    let limits = STRING_CACHE.limits.clone();
+    // This storage isn't checked against the budget like the string cache's
+    // is, but it's small and it counts towards it.
+    limits.add_bytes(config.bin_alloc() * config.num_bins);
+    let max_len = if strings { config.max_string_len } else { None };
+    let bins = Bins::with_limits(&config, limits, max_len);
+    let bins = Box::leak(Box::new(bins));
+    OTHER_CACHES.lock().push(bins);
+    bins
 }
@@ -0,0 +1,249 @@
+use super::{CacheConfig, Ustr};
+use std::{
+    fmt,
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Error returned when a string could not be interned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InternError {
+    /// The system allocator could not provide the memory for the string or
+    /// for growing the cache.
+    OutOfMemory,
+    /// Storing the string would take the cache over its configured memory
+    /// budget (see [`CacheConfig::memory_budget`](crate::CacheConfig)).
+    BudgetExceeded,
+    /// The cache already holds the configured maximum number of strings (see
+    /// [`CacheConfig::max_entries`](crate::CacheConfig)).
+    TooManyEntries,
+    /// The string is longer than the configured maximum length (see
+    /// [`CacheConfig::max_string_len`](crate::CacheConfig)).
+    StringTooLong,
+}
+
+impl InternError {
+    /// Returns true if the error was caused by one of the configured limits
+    /// rather than by running out of memory.
+    pub fn is_limit(&self) -> bool {
+        match self {
+            InternError::OutOfMemory => false,
+            InternError::BudgetExceeded
+            | InternError::TooManyEntries
+            | InternError::StringTooLong => true,
+        }
+    }
+}
+
+impl fmt::Display for InternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InternError::OutOfMemory => {
+                write!(f, "out of memory while interning string")
+            }
+            InternError::BudgetExceeded => {
+                write!(f, "string cache memory budget exceeded")
+            }
+            InternError::TooManyEntries => {
+                write!(f, "string cache entry limit exceeded")
+            }
+            InternError::StringTooLong => {
+                write!(f, "string is longer than the maximum allowed length")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InternError {}
+
+/// A string that was either interned, or copied because interning it would
+/// have gone over the cache's limits.
+///
+/// Returned by [`ustr_or_owned()`](crate::ustr_or_owned).
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum MaybeInterned {
+    /// The string is in the cache.
+    Interned(Ustr),
+    /// The string is not in the cache.
+    Owned(Box<str>),
+}
+
+impl MaybeInterned {
+    /// Get the string as a `str`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            MaybeInterned::Interned(u) => u.as_str(),
+            MaybeInterned::Owned(s) => s,
+        }
+    }
+
+    /// Get the `Ustr` if the string was interned.
+    pub fn as_ustr(&self) -> Option<Ustr> {
+        match self {
+            MaybeInterned::Interned(u) => Some(*u),
+            MaybeInterned::Owned(_) => None,
+        }
+    }
+
+    /// Returns true if the string was interned.
+    pub fn is_interned(&self) -> bool {
+        matches!(self, MaybeInterned::Interned(_))
+    }
+}
+
+impl Deref for MaybeInterned {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl PartialEq<str> for MaybeInterned {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for MaybeInterned {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Display for MaybeInterned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for MaybeInterned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaybeInterned::Interned(u) => write!(f, "{:?}", u),
+            MaybeInterned::Owned(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+// Limits shared by all the bins of a cache, or by all the global caches. The
+// bins each have their own lock so the running totals are kept in atomics.
+pub(crate) struct Limits {
+    // Maximum number of bytes of arena storage across all bins.
+    max_bytes: usize,
+    // Number of bytes of arena storage currently reserved.
+    bytes: AtomicUsize,
+    // Maximum number of strings across all bins.
+    max_entries: usize,
+    // Number of strings currently stored or being inserted.
+    entries: AtomicUsize,
+}
+
+impl Limits {
+    pub(crate) fn new(config: &CacheConfig) -> Limits {
+        Limits {
+            max_bytes: config.memory_budget.unwrap_or(usize::MAX),
+            bytes: AtomicUsize::new(0),
+            max_entries: config.max_entries.unwrap_or(usize::MAX),
+            entries: AtomicUsize::new(0),
+        }
+    }
+
+    // Reserve room for one more string.
+    pub(crate) fn reserve_entry(&self) -> Result<(), InternError> {
+        self.entries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                if n < self.max_entries {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .map(|_| ())
+            .map_err(|_| InternError::TooManyEntries)
+    }
+
+    pub(crate) fn release_entry(&self) {
+        self.entries.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Reserve between `min` and `desired` bytes of arena storage, returning
+    // the number of bytes granted, which is a multiple of `align`. `min` and
+    // `desired` must be multiples of `align` too.
+    pub(crate) fn reserve_bytes(
+        &self,
+        min: usize,
+        desired: usize,
+        align: usize,
+    ) -> Result<usize, InternError> {
+        let mut current = self.bytes.load(Ordering::Relaxed);
+        loop {
+            let remaining = self.max_bytes.saturating_sub(current);
+            if remaining < min {
+                return Err(InternError::BudgetExceeded);
+            }
+            let granted = desired.min(remaining & !(align - 1));
+            match self.bytes.compare_exchange_weak(
+                current,
+                current + granted,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(granted),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    pub(crate) fn release_bytes(&self, bytes: usize) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // Count storage that has already been allocated, even if it goes over
+    // the budget.
+    pub(crate) fn add_bytes(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Reset the running totals, used when clearing the cache.
+    pub(crate) fn reset(&self, bytes: usize) {
+        self.bytes.store(bytes, Ordering::Relaxed);
+        self.entries.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InternError, Limits};
+    use crate::configure;
+
+    #[test]
+    fn reserve() {
+        let limits = Limits::new(&configure().memory_budget(100));
+        assert_eq!(limits.reserve_bytes(16, 64, 8), Ok(64));
+        // Only 36 bytes left, rounded down to the alignment.
+        assert_eq!(limits.reserve_bytes(16, 64, 8), Ok(32));
+        assert_eq!(
+            limits.reserve_bytes(8, 8, 8),
+            Err(InternError::BudgetExceeded)
+        );
+        limits.release_bytes(64);
+        assert_eq!(limits.reserve_bytes(16, 64, 8), Ok(64));
+
+        let unlimited = Limits::new(&configure());
+        assert_eq!(unlimited.reserve_bytes(8, 1 << 40, 8), Ok(1 << 40));
+    }
+
+    #[test]
+    fn entries() {
+        let limits = Limits::new(&configure().max_entries(2));
+        assert_eq!(limits.reserve_entry(), Ok(()));
+        assert_eq!(limits.reserve_entry(), Ok(()));
+        assert_eq!(limits.reserve_entry(), Err(InternError::TooManyEntries));
+        limits.release_entry();
+        assert_eq!(limits.reserve_entry(), Ok(()));
+
+        limits.reset(0);
+        assert_eq!(limits.reserve_entry(), Ok(()));
+    }
+}
@@ -0,0 +1,292 @@
+use super::{InternError, Ustr};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+};
+use unicode_normalization::{
+    is_nfc_quick, is_nfkc_quick, IsNormalized, UnicodeNormalization,
+};
+
+/// A handle representing a string in Unicode Normalization Form C.
+///
+/// Text from different sources can spell the same characters in different
+/// ways, such as `é` as a single code point or as `e` followed by a combining
+/// accent, and `Ustr::from` treats those as different strings. A `UstrNfc` is
+/// a `Ustr` that has been normalized before it was interned, so equal text
+/// gives equal handles however it was spelled.
+///
+/// It has the same pointer equality and precomputed hash as the `Ustr` it
+/// wraps, and pure ASCII strings, which are always normalized, are interned
+/// without any extra work.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ustr, UstrNfc};
+///
+/// let composed = UstrNfc::from("caf\u{e9}");
+/// let decomposed = UstrNfc::from("cafe\u{301}");
+/// assert_eq!(composed, decomposed);
+/// assert_eq!(decomposed.as_ustr(), ustr("caf\u{e9}"));
+/// assert_eq!(composed, "cafe\u{301}");
+///
+/// // NFKC also folds compatibility characters.
+/// assert_ne!(UstrNfc::from("\u{fb01}le"), UstrNfc::from("file"));
+/// assert_eq!(UstrNfc::from_nfkc("\u{fb01}le"), UstrNfc::from("file"));
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct UstrNfc {
+    ustr: Ustr,
+}
+
+impl UstrNfc {
+    /// Normalize the given string to NFC and intern it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be interned, either because we ran out of
+    /// memory or because one of the configured limits was reached. Use
+    /// [`UstrNfc::try_from`] to handle that instead.
+    pub fn from(string: &str) -> UstrNfc {
+        UstrNfc::try_from(string)
+            .unwrap_or_else(|e| panic!("failed to intern string: {}", e))
+    }
+
+    /// Normalize the given string to NFC and intern it, returning an error
+    /// rather than panicking if it can't be stored.
+    pub fn try_from(string: &str) -> Result<UstrNfc, InternError> {
+        Ustr::try_from(&nfc(string)).map(|ustr| UstrNfc { ustr })
+    }
+
+    /// Normalize the given string to NFKC and intern it.
+    ///
+    /// NFKC also replaces compatibility characters such as ligatures and
+    /// full-width forms with their plain equivalents, which is what you want
+    /// for identifiers, as in [UAX #31]. The result is in NFC too.
+    ///
+    /// [UAX #31]: https://www.unicode.org/reports/tr31/
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be interned. Use [`UstrNfc::try_from_nfkc`]
+    /// to handle that instead.
+    pub fn from_nfkc(string: &str) -> UstrNfc {
+        UstrNfc::try_from_nfkc(string)
+            .unwrap_or_else(|e| panic!("failed to intern string: {}", e))
+    }
+
+    /// Normalize the given string to NFKC and intern it, returning an error
+    /// rather than panicking if it can't be stored.
+    pub fn try_from_nfkc(string: &str) -> Result<UstrNfc, InternError> {
+        Ustr::try_from(&nfkc(string)).map(|ustr| UstrNfc { ustr })
+    }
+
+    /// Get the normalized string as a `Ustr`.
+    pub fn as_ustr(&self) -> Ustr {
+        self.ustr
+    }
+
+    /// Get the normalized string.
+    pub fn as_str(&self) -> &'static str {
+        self.ustr.as_str()
+    }
+
+    /// Get the precomputed hash of the normalized string.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        self.ustr.precomputed_hash()
+    }
+}
+
+// ASCII is the same in every normalization form, so check for it first, which
+// is much cheaper than the quick check. Only strings the quick check isn't
+// sure about get copied.
+fn nfc(string: &str) -> Cow<'_, str> {
+    if string.is_ascii() || is_nfc_quick(string.chars()) == IsNormalized::Yes {
+        Cow::Borrowed(string)
+    } else {
+        Cow::Owned(string.nfc().collect())
+    }
+}
+
+fn nfkc(string: &str) -> Cow<'_, str> {
+    if string.is_ascii() || is_nfkc_quick(string.chars()) == IsNormalized::Yes {
+        Cow::Borrowed(string)
+    } else {
+        Cow::Owned(string.nfkc().collect())
+    }
+}
+
+/// Normalize the given string to NFC and intern it.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ustr, ustr_nfc};
+///
+/// assert_eq!(ustr_nfc("A\u{30a}ngstro\u{308}m"), ustr("\u{c5}ngstr\u{f6}m"));
+/// ```
+#[inline]
+pub fn ustr_nfc(s: &str) -> Ustr {
+    UstrNfc::from(s).as_ustr()
+}
+
+/// Normalize the given string to NFKC and intern it.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ustr, ustr_nfkc};
+///
+/// assert_eq!(ustr_nfkc("\u{ff49}d\u{2081}"), ustr("id1"));
+/// ```
+#[inline]
+pub fn ustr_nfkc(s: &str) -> Ustr {
+    UstrNfc::from_nfkc(s).as_ustr()
+}
+
+impl PartialEq<str> for UstrNfc {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == nfc(other)
+    }
+}
+
+impl PartialEq<&str> for UstrNfc {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq<Ustr> for UstrNfc {
+    fn eq(&self, other: &Ustr) -> bool {
+        self == other.as_str()
+    }
+}
+
+impl Ord for UstrNfc {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ustr.cmp(&other.ustr)
+    }
+}
+
+impl PartialOrd for UstrNfc {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, as for `Ustr`.
+impl Hash for UstrNfc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+impl From<&str> for UstrNfc {
+    fn from(s: &str) -> UstrNfc {
+        UstrNfc::from(s)
+    }
+}
+
+impl From<Ustr> for UstrNfc {
+    fn from(u: Ustr) -> UstrNfc {
+        match nfc(u.as_str()) {
+            Cow::Borrowed(_) => UstrNfc { ustr: u },
+            Cow::Owned(s) => UstrNfc::from(&s),
+        }
+    }
+}
+
+impl From<UstrNfc> for Ustr {
+    fn from(u: UstrNfc) -> Ustr {
+        u.as_ustr()
+    }
+}
+
+impl Default for UstrNfc {
+    fn default() -> Self {
+        UstrNfc::from("")
+    }
+}
+
+impl Deref for UstrNfc {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for UstrNfc {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for UstrNfc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for UstrNfc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "nfc!({:?})", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nfc, ustr_nfc, ustr_nfkc, UstrNfc};
+    use crate::{existing_ustr, ustr, Ustr, UstrNfcSet};
+    use std::borrow::Cow;
+
+    #[test]
+    fn normalized() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        // Hangul syllables compose algorithmically.
+        let a = UstrNfc::from("\u{1112}\u{1161}\u{11ab}");
+        assert_eq!(a, UstrNfc::from("\u{d55c}"));
+        assert_eq!(a.as_str(), "\u{d55c}");
+        assert_eq!(a, "\u{1112}\u{1161}\u{11ab}");
+        assert_eq!(a, ustr("\u{d55c}"));
+        assert_eq!(a.precomputed_hash(), ustr("\u{d55c}").precomputed_hash());
+        assert_eq!(format!("{:?}", UstrNfc::from("e\u{301}")), "nfc!(\"é\")");
+
+        // Canonical ordering of combining marks.
+        assert_eq!(ustr_nfc("q\u{307}\u{323}"), ustr("q\u{323}\u{307}"));
+        // Singletons are replaced even in NFC.
+        assert_eq!(ustr_nfc("\u{212b}"), ustr("\u{c5}"));
+        // Compatibility characters are only replaced in NFKC.
+        assert_eq!(ustr_nfc("x\u{b2}"), ustr("x\u{b2}"));
+        assert_eq!(ustr_nfkc("x\u{b2}"), ustr("x2"));
+        assert_eq!(ustr_nfkc("\u{fb03}"), ustr("ffi"));
+
+        assert_eq!(
+            <UstrNfc as From<_>>::from(ustr("o\u{308}")),
+            UstrNfc::from("\u{f6}")
+        );
+        assert_eq!(<Ustr as From<_>>::from(a), ustr("\u{d55c}"));
+
+        let set: UstrNfcSet = ["\u{f6}", "o\u{308}", "o"]
+            .into_iter()
+            .map(UstrNfc::from)
+            .collect();
+        assert_eq!(set.len(), 2);
+        // Only the normalized spellings are interned.
+        assert_eq!(existing_ustr("e\u{301}"), None);
+        assert_eq!(existing_ustr("\u{212b}"), None);
+    }
+
+    #[test]
+    fn fast_path() {
+        assert!(matches!(nfc("plain ascii"), Cow::Borrowed(_)));
+        assert!(matches!(nfc("d\u{e9}j\u{e0} vu"), Cow::Borrowed(_)));
+        assert!(matches!(nfc("de\u{301}ja\u{300} vu"), Cow::Owned(_)));
+    }
+}
@@ -0,0 +1,3 @@
+//! Extra ways of serializing `Ustr`s with serde, beyond writing out each one
+//! as a string.
+pub mod table;
@@ -0,0 +1,606 @@
+//! Serialize `Ustr`s as indices into a table of strings that is written once,
+//! rather than writing out the text of every one.
+//!
+//! This is useful for data where the same few strings turn up over and over,
+//! such as the identifiers in a syntax tree. Mark each `Ustr` field with
+//! `#[serde(with = "ustr::serde::table")]` and wrap the top-level value in a
+//! [`Tabled`]. The wrapper is serialized as a pair of the string table and
+//! the value, with each marked field written as its index in the table. When
+//! it's deserialized, the strings in the table are interned and the fields
+//! are looked up in it.
+//!
+//! Marked fields can only be serialized and deserialized inside a `Tabled`,
+//! and return an error otherwise.
+//!
+//! # Examples
+//!
+//! ```
+//! use serde::{Deserialize, Serialize};
+//! use ustr::{serde::table::Tabled, ustr, Ustr};
+//!
+//! #[derive(Serialize, Deserialize, PartialEq, Debug)]
+//! struct Call {
+//!     #[serde(with = "ustr::serde::table")]
+//!     function: Ustr,
+//!     args: Vec<Arg>,
+//! }
+//!
+//! #[derive(Serialize, Deserialize, PartialEq, Debug)]
+//! struct Arg {
+//!     #[serde(with = "ustr::serde::table")]
+//!     name: Ustr,
+//! }
+//!
+//! let call = Call {
+//!     function: ustr("draw"),
+//!     args: vec![Arg { name: ustr("x") }, Arg { name: ustr("x") }],
+//! };
+//! let json = serde_json::to_string(&Tabled(&call)).unwrap();
+//! assert_eq!(
+//!     json,
+//!     r#"[["draw","x"],{"function":0,"args":[{"name":1},{"name":1}]}]"#
+//! );
+//!
+//! let Tabled(loaded): Tabled<Call> = serde_json::from_str(&json).unwrap();
+//! assert_eq!(loaded, call);
+//! ```
+use crate::{Ustr, UstrMap};
+use ::serde::{
+    de::{self, Deserialize, Deserializer, SeqAccess, Visitor},
+    ser::{self, Serialize, SerializeTuple, Serializer},
+};
+use std::{cell::RefCell, fmt, marker::PhantomData};
+
+/// Serialize a `Ustr` field as its index in the string table of the enclosing
+/// [`Tabled`].
+///
+/// This is meant to be used with `#[serde(with = "ustr::serde::table")]`.
+pub fn serialize<S>(u: &Ustr, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let index = STATE.with(|state| match &mut *state.borrow_mut() {
+        Some(State::Collecting(table)) => Ok(table.index_of(*u)),
+        Some(State::Writing(table)) => {
+            table.indices.get(u).copied().ok_or_else(|| {
+                ser::Error::custom(format!(
+                    "{:?} was not in the string table",
+                    u
+                ))
+            })
+        }
+        _ => Err(ser::Error::custom(
+            "ustr::serde::table used outside of a Tabled value",
+        )),
+    })?;
+    serializer.serialize_u32(index)
+}
+
+/// Deserialize a `Ustr` field from its index in the string table of the
+/// enclosing [`Tabled`].
+///
+/// This is meant to be used with `#[serde(with = "ustr::serde::table")]`.
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Ustr, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let index = u32::deserialize(deserializer)?;
+    STATE.with(|state| match &*state.borrow() {
+        Some(State::Reading(strings)) => {
+            strings.get(index as usize).copied().ok_or_else(|| {
+                de::Error::custom(format!(
+                    "string table index {} is out of range",
+                    index
+                ))
+            })
+        }
+        _ => Err(de::Error::custom(
+            "ustr::serde::table used outside of a Tabled value",
+        )),
+    })
+}
+
+/// A value whose `Ustr` fields marked with
+/// `#[serde(with = "ustr::serde::table")]` are serialized as indices into a
+/// string table.
+///
+/// Serializing a `Tabled` goes over the value twice: once to find the strings
+/// for the table, and once to write it out. `Tabled(&value)` can be used to
+/// serialize a value without moving it.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Tabled<T>(pub T);
+
+impl<T> Tabled<T> {
+    /// Unwrap the value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: Serialize> Serialize for Tabled<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // Find every string the value uses, in the order it uses them.
+        let table = {
+            let state =
+                StateGuard::set(State::Collecting(StringTable::default()));
+            self.0.serialize(Collector).map_err(ser::Error::custom)?;
+            match state.take() {
+                Some(State::Collecting(table)) => table,
+                _ => unreachable!("string table state was changed"),
+            }
+        };
+
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&table.strings)?;
+        let _state = StateGuard::set(State::Writing(table));
+        tuple.serialize_element(&self.0)?;
+        tuple.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Tabled<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Tabled<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_tuple(2, TabledVisitor(PhantomData))
+    }
+}
+
+struct TabledVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for TabledVisitor<T> {
+    type Value = Tabled<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string table followed by a value")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        // Deserializing the table interns all its strings.
+        let strings: Vec<Ustr> = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let _state = StateGuard::set(State::Reading(strings));
+        let value = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok(Tabled(value))
+    }
+}
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<Ustr>,
+    indices: UstrMap<u32>,
+}
+
+impl StringTable {
+    fn index_of(&mut self, u: Ustr) -> u32 {
+        let next = self.strings.len() as u32;
+        *self.indices.entry(u).or_insert_with(|| {
+            self.strings.push(u);
+            next
+        })
+    }
+}
+
+enum State {
+    Collecting(StringTable),
+    Writing(StringTable),
+    Reading(Vec<Ustr>),
+}
+
+thread_local! {
+    // The table for the innermost `Tabled` being serialized or deserialized
+    // on this thread.
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+// Sets the state for a `Tabled`, and puts back the state of any `Tabled` it's
+// nested in when dropped, even if serializing panics.
+struct StateGuard {
+    previous: Option<State>,
+}
+
+impl StateGuard {
+    fn set(state: State) -> StateGuard {
+        let previous = STATE.with(|s| s.borrow_mut().replace(state));
+        StateGuard { previous }
+    }
+
+    // Take the current state, leaving the previous one to be restored.
+    fn take(&self) -> Option<State> {
+        STATE.with(|s| s.borrow_mut().take())
+    }
+}
+
+impl Drop for StateGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        STATE.with(|s| *s.borrow_mut() = previous);
+    }
+}
+
+// A serializer that throws everything away. Serializing a value with it runs
+// the `serialize()` function above for every marked field, which collects the
+// strings into the table.
+struct Collector;
+
+#[derive(Debug)]
+struct CollectError(String);
+
+impl fmt::Display for CollectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CollectError {}
+
+impl ser::Error for CollectError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        CollectError(msg.to_string())
+    }
+}
+
+type CollectResult = Result<(), CollectError>;
+
+impl Serializer for Collector {
+    type Ok = ();
+    type Error = CollectError;
+    type SerializeSeq = Collector;
+    type SerializeTuple = Collector;
+    type SerializeTupleStruct = Collector;
+    type SerializeTupleVariant = Collector;
+    type SerializeMap = Collector;
+    type SerializeStruct = Collector;
+    type SerializeStructVariant = Collector;
+
+    fn serialize_bool(self, _: bool) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_i8(self, _: i8) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_i16(self, _: i16) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_i32(self, _: i32) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_i64(self, _: i64) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_i128(self, _: i128) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_u8(self, _: u8) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_u16(self, _: u16) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_u32(self, _: u32) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_u64(self, _: u64) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_u128(self, _: u128) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_f32(self, _: f32) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_f64(self, _: f64) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_char(self, _: char) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_str(self, _: &str) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_bytes(self, _: &[u8]) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_none(self) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> CollectResult {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> CollectResult {
+        Ok(())
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(self)
+    }
+    fn serialize_seq(
+        self,
+        _: Option<usize>,
+    ) -> Result<Collector, CollectError> {
+        Ok(self)
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Collector, CollectError> {
+        Ok(self)
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Collector, CollectError> {
+        Ok(self)
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Collector, CollectError> {
+        Ok(self)
+    }
+    fn serialize_map(
+        self,
+        _: Option<usize>,
+    ) -> Result<Collector, CollectError> {
+        Ok(self)
+    }
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Collector, CollectError> {
+        Ok(self)
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Collector, CollectError> {
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for Collector {
+    type Ok = ();
+    type Error = CollectError;
+    fn serialize_element<T: ?Sized + Serialize>(
+        &mut self,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(Collector)
+    }
+    fn end(self) -> CollectResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for Collector {
+    type Ok = ();
+    type Error = CollectError;
+    fn serialize_element<T: ?Sized + Serialize>(
+        &mut self,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(Collector)
+    }
+    fn end(self) -> CollectResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for Collector {
+    type Ok = ();
+    type Error = CollectError;
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(Collector)
+    }
+    fn end(self) -> CollectResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for Collector {
+    type Ok = ();
+    type Error = CollectError;
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(Collector)
+    }
+    fn end(self) -> CollectResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for Collector {
+    type Ok = ();
+    type Error = CollectError;
+    fn serialize_key<T: ?Sized + Serialize>(
+        &mut self,
+        key: &T,
+    ) -> CollectResult {
+        key.serialize(Collector)
+    }
+    fn serialize_value<T: ?Sized + Serialize>(
+        &mut self,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(Collector)
+    }
+    fn end(self) -> CollectResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for Collector {
+    type Ok = ();
+    type Error = CollectError;
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        _: &'static str,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(Collector)
+    }
+    fn end(self) -> CollectResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for Collector {
+    type Ok = ();
+    type Error = CollectError;
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        _: &'static str,
+        value: &T,
+    ) -> CollectResult {
+        value.serialize(Collector)
+    }
+    fn end(self) -> CollectResult {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tabled;
+    use crate::{ustr, Ustr};
+    use ::serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Expr {
+        Var(#[serde(with = "super")] Ustr),
+        Call {
+            #[serde(with = "super")]
+            function: Ustr,
+            args: Vec<Expr>,
+        },
+        Literal(i64),
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Function {
+        #[serde(with = "super")]
+        name: Ustr,
+        body: Option<Expr>,
+    }
+
+    fn ast() -> Vec<Function> {
+        let call = |args| Expr::Call {
+            function: ustr("add"),
+            args,
+        };
+        (0..100)
+            .map(|n| Function {
+                name: ustr(&format!("f{}", n % 10)),
+                body: Some(call(vec![
+                    Expr::Var(ustr("x")),
+                    call(vec![Expr::Var(ustr("y")), Expr::Literal(n)]),
+                ])),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let _t = crate::TEST_LOCK.lock();
+        let ast = ast();
+        let json = serde_json::to_string(&Tabled(&ast)).unwrap();
+        // Every string is written out exactly once.
+        assert_eq!(json.matches("\"add\"").count(), 1);
+        assert_eq!(json.matches("\"f3\"").count(), 1);
+        assert_eq!(json.matches("\"x\"").count(), 1);
+
+        let Tabled(loaded): Tabled<Vec<Function>> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, ast);
+    }
+
+    #[test]
+    fn nested() {
+        let _t = crate::TEST_LOCK.lock();
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Outer {
+            #[serde(with = "super")]
+            name: Ustr,
+            inner: Tabled<Function>,
+            #[serde(with = "super")]
+            after: Ustr,
+        }
+
+        let outer = Outer {
+            name: ustr("outer"),
+            inner: Tabled(Function {
+                name: ustr("inner"),
+                body: None,
+            }),
+            after: ustr("after"),
+        };
+        let json = serde_json::to_string(&Tabled(&outer)).unwrap();
+        let Tabled(loaded): Tabled<Outer> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, outer);
+    }
+
+    #[test]
+    fn outside_tabled() {
+        let _t = crate::TEST_LOCK.lock();
+        let f = Function {
+            name: ustr("f"),
+            body: None,
+        };
+        assert!(serde_json::to_string(&f).is_err());
+        assert!(
+            serde_json::from_str::<Function>(r#"{"name":0,"body":null}"#)
+                .is_err()
+        );
+        assert!(serde_json::from_str::<Tabled<Function>>(
+            r#"[["f"],{"name":1,"body":null}]"#
+        )
+        .is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use super::*;
-use serde::{
-    de::{Deserialize, Deserializer, Error, SeqAccess, Visitor},
+use ::serde::{
+    de::{Deserialize, Deserializer, Error, SeqAccess, Unexpected, Visitor},
     ser::{Serialize, SerializeSeq, Serializer},
 };
 
@@ -9,20 +9,67 @@ impl Serialize for Bins {
     where
         S: Serializer,
     {
-        let strings: Vec<&'static str> = string_cache_iter().collect();
-        let mut seq = serializer.serialize_seq(Some(strings.len()))?;
-        for s in strings {
-            match seq.serialize_element(s) {
-                Ok(_) => (),
-                Err(e) => {
-                    panic!("Error serializing \"{}\": {}", s, e);
-                }
-            }
-        }
-        seq.end()
+        serialize_strings(self.iter(), serializer)
     }
 }
 
+impl Bins {
+    /// Get a view of the cache that serializes exactly the strings that were
+    /// in it at a single point in time.
+    ///
+    /// Serializing [`cache()`] directly walks the bins one at a time, so if
+    /// other threads are interning strings while it runs, the output might
+    /// include a string without one that was interned before it. This takes
+    /// all the bins' locks at once while it finds the strings to write, which
+    /// briefly blocks interning new strings on other threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::ustr;
+    /// ustr("Send me to JSON and back");
+    /// let json = serde_json::to_string(&ustr::cache().consistent()).unwrap();
+    /// ```
+    pub fn consistent(&self) -> ConsistentCache<'_> {
+        ConsistentCache { bins: self }
+    }
+}
+
+/// A view of a cache that serializes a consistent snapshot of its strings.
+///
+/// Returned by [`Bins::consistent()`].
+pub struct ConsistentCache<'a> {
+    bins: &'a Bins,
+}
+
+impl Serialize for ConsistentCache<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serialize_strings(self.bins.iter_consistent(), serializer)
+    }
+}
+
+// Stream the strings straight from the iterator. We need the length up front
+// for formats that write it before the elements, and since the iterator only
+// covers the allocators that existed when it was made, counting a clone of it
+// gives the number of strings the original will produce.
+fn serialize_strings<S>(
+    iter: StringCacheIterator,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let len = iter.clone().count();
+    let mut seq = serializer.serialize_seq(Some(len))?;
+    for s in iter {
+        seq.serialize_element(s)?;
+    }
+    seq.end()
+}
+
 pub struct BinsVisitor {}
 
 impl BinsVisitor {
@@ -43,9 +90,9 @@ impl<'de> Visitor<'de> for BinsVisitor {
     where
         A: SeqAccess<'de>,
     {
-        while let Some(s) = seq.next_element::<String>()? {
-            ustr(&s);
-        }
+        // Deserializing each element as a `Ustr` interns it straight from
+        // whatever the format hands us, without going through a `String`.
+        while seq.next_element::<Ustr>()?.is_some() {}
 
         Ok(DeserializedCache {})
     }
@@ -74,14 +121,25 @@ impl<'de> Visitor<'de> for UstrVisitor {
     type Value = Ustr;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a &str")
+        formatter.write_str("a string")
     }
 
+    // The borrowed and owned variants of these forward to them by default,
+    // and all of them intern straight from the slice they're given.
     fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
     where
         E: Error,
     {
-        Ok(Ustr::from(s))
+        Ustr::try_from(s).map_err(E::custom)
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        let s = std::str::from_utf8(bytes)
+            .map_err(|_| E::invalid_value(Unexpected::Bytes(bytes), &self))?;
+        self.visit_str(s)
     }
 }
 
@@ -102,3 +160,42 @@ impl Serialize for Ustr {
         serializer.serialize_str(self.as_str())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{ustr, Ustr};
+    use ::serde::{
+        de::value::{
+            BorrowedBytesDeserializer, BorrowedStrDeserializer,
+            BytesDeserializer, Error, StringDeserializer,
+        },
+        Deserialize,
+    };
+
+    #[test]
+    fn deserialize_inputs() {
+        let _t = crate::TEST_LOCK.lock();
+        let hello = ustr("hello");
+
+        let borrowed = BorrowedStrDeserializer::<Error>::new("hello");
+        assert_eq!(Ustr::deserialize(borrowed).unwrap(), hello);
+        let owned = StringDeserializer::<Error>::new("hello".to_owned());
+        assert_eq!(Ustr::deserialize(owned).unwrap(), hello);
+        let bytes = BytesDeserializer::<Error>::new(b"hello");
+        assert_eq!(Ustr::deserialize(bytes).unwrap(), hello);
+        let borrowed = BorrowedBytesDeserializer::<Error>::new(b"hello");
+        assert_eq!(Ustr::deserialize(borrowed).unwrap(), hello);
+
+        // JSON hands out borrowed strings unless there are escapes to
+        // replace.
+        let escaped: Ustr = serde_json::from_str(r#""hel\u006co""#).unwrap();
+        assert_eq!(escaped, hello);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let bytes = BytesDeserializer::<Error>::new(b"\xffhello");
+        let e = Ustr::deserialize(bytes).unwrap_err();
+        assert!(e.to_string().contains("expected a string"), "{}", e);
+    }
+}
@@ -0,0 +1,413 @@
+//! Saving and restoring the contents of the global cache in a compact binary
+//! format.
+//!
+//! This is much faster than going through serde for warm-starting a process
+//! with the strings a previous run interned, since the strings are added in
+//! bulk, taking the lock for each bin only once.
+//!
+//! # Examples
+//!
+//! ```
+//! use ustr::ustr;
+//!
+//! ustr("Send me to disk and back");
+//! let mut bytes = Vec::new();
+//! ustr::snapshot::write(&mut bytes).unwrap();
+//!
+//! // ... in another process ...
+//! # unsafe { ustr::_clear_cache() };
+//! let loaded = ustr::snapshot::load(&mut bytes.as_slice()).unwrap();
+//! assert_eq!(loaded, 1);
+//! assert!(ustr::existing_ustr("Send me to disk and back").is_some());
+//! ```
+//!
+//! # Format
+//!
+//! All integers are little-endian.
+//!
+//! | Field      | Size      | Contents                                      |
+//! |------------|-----------|-----------------------------------------------|
+//! | magic      | 8         | `b"USTRSNAP"`                                 |
+//! | version    | 4         | format version, currently 1                   |
+//! | algorithm  | 4         | id of the hash function used for the hashes  |
+//! | check      | 8         | hash of [`HASH_CHECK_STRING`]                 |
+//! | count      | 8         | number of strings                             |
+//! | strings    | count × … | hash (8), length (8) and bytes of each string |
+//! | checksum   | 8         | [`stable_hash`] of everything before it       |
+//!
+//! The check hash catches the case where the same hash function gives
+//! different results in different builds, e.g. because it uses different
+//! instructions depending on the CPU. When the hash function is the same,
+//! the stored hashes are used as they are, and the strings aren't hashed
+//! again. Debug builds still check every stored hash against its string.
+//!
+//! [`stable_hash`]: crate::stable_hash
+use super::{
+    hash::stable_hash_bytes, Bins, InternError, StringCacheEntry, STRING_CACHE,
+};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+#[cfg(test)]
+use std::cell::Cell;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+const MAGIC: &[u8; 8] = b"USTRSNAP";
+const VERSION: u32 = 1;
+// Size of everything before the strings.
+const HEADER_LEN: usize = 32;
+
+/// The string whose hash is stored in the header to check that the hash
+/// function gives the same results when loading as when writing.
+pub const HASH_CHECK_STRING: &str = "ustr snapshot hash check";
+
+/// Error returned when loading a snapshot.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    /// Reading the snapshot failed.
+    Io(io::Error),
+    /// The data doesn't start with the snapshot magic bytes.
+    BadMagic,
+    /// The snapshot was written with an unsupported format version.
+    UnsupportedVersion(u32),
+    /// The data ended before the end of the snapshot.
+    Truncated,
+    /// The checksum doesn't match the contents.
+    ChecksumMismatch,
+    /// A string in the snapshot is not valid UTF-8.
+    InvalidUtf8,
+    /// A stored hash doesn't match its string, although the snapshot was
+    /// written with the same hash function. This is only checked in debug
+    /// builds.
+    HashMismatch,
+    /// A string in the snapshot could not be interned.
+    Intern(InternError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "error reading snapshot: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a ustr snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::ChecksumMismatch => {
+                write!(f, "snapshot checksum does not match")
+            }
+            SnapshotError::InvalidUtf8 => {
+                write!(f, "snapshot contains invalid UTF-8")
+            }
+            SnapshotError::HashMismatch => {
+                write!(f, "snapshot contains a string with the wrong hash")
+            }
+            SnapshotError::Intern(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            SnapshotError::Intern(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<InternError> for SnapshotError {
+    fn from(e: InternError) -> Self {
+        SnapshotError::Intern(e)
+    }
+}
+
+/// Write every string in the global cache to `writer`.
+///
+/// The strings written are exactly those that were in the cache at one point
+/// in time, so if other threads are adding strings while this runs, any
+/// string that's included comes with every string added before it.
+pub fn write<W: Write>(writer: &mut W) -> io::Result<()> {
+    write_bins(&STRING_CACHE, writer)
+}
+
+/// Intern every string in the snapshot read from `reader` into the global
+/// cache, returning the number of strings in the snapshot.
+///
+/// The whole snapshot is read and checked before anything is interned. If the
+/// hash function matches the one used to write the snapshot, every stored
+/// hash has to match its string, otherwise the hashes are just recomputed.
+pub fn load<R: Read>(reader: &mut R) -> Result<usize, SnapshotError> {
+    load_bins(&STRING_CACHE, reader)
+}
+
+pub(crate) fn write_bins<W: Write>(
+    bins: &Bins,
+    writer: &mut W,
+) -> io::Result<()> {
+    let iter = bins.iter_consistent();
+    let count = iter.clone().count();
+
+    // The checksum covers everything before it, so build it all up first.
+    let mut body = Vec::new();
+    body.write_all(MAGIC)?;
+    body.write_u32::<LittleEndian>(VERSION)?;
+    body.write_u32::<LittleEndian>(bins.hasher.id())?;
+    body.write_u64::<LittleEndian>(bins.hash(HASH_CHECK_STRING))?;
+    body.write_u64::<LittleEndian>(count as u64)?;
+    for s in iter.take(count) {
+        // The iterator gives us slices of the chars of each entry.
+        let sce = unsafe { StringCacheEntry::from_char_ptr(s.as_ptr()) };
+        body.write_u64::<LittleEndian>(sce.hash)?;
+        body.write_u64::<LittleEndian>(s.len() as u64)?;
+        body.write_all(s.as_bytes())?;
+    }
+    let checksum = stable_hash_bytes(&body);
+    writer.write_all(&body)?;
+    writer.write_u64::<LittleEndian>(checksum)
+}
+
+pub(crate) fn load_bins<R: Read>(
+    bins: &Bins,
+    reader: &mut R,
+) -> Result<usize, SnapshotError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    if data.len() < HEADER_LEN + 8 {
+        return Err(SnapshotError::Truncated);
+    }
+    let version = LittleEndian::read_u32(&data[8..12]);
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let (body, checksum) = data.split_at(data.len() - 8);
+    if stable_hash_bytes(body) != LittleEndian::read_u64(checksum) {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+
+    let algorithm = LittleEndian::read_u32(&body[12..16]);
+    let check = LittleEndian::read_u64(&body[16..24]);
+    let count = LittleEndian::read_u64(&body[24..32]);
+    let same_hasher =
+        algorithm == bins.hasher.id() && check == bins.hash(HASH_CHECK_STRING);
+
+    // Check the whole thing is well-formed before interning anything, so
+    // that a bad snapshot doesn't leave us with half of it loaded.
+    let mut strings = Vec::new();
+    let mut hashes = Vec::new();
+    for_each_string(&body[HEADER_LEN..], count, |stored, s| {
+        let hash = if !same_hasher {
+            bins.hash(s)
+        } else if check_stored_hashes() && bins.hash(s) != stored {
+            return Err(SnapshotError::HashMismatch);
+        } else {
+            stored
+        };
+        strings.push(s);
+        hashes.push(hash);
+        Ok(())
+    })?;
+    bins.insert_many_hashed(&strings, &hashes, |_, _| {})?;
+
+    Ok(strings.len())
+}
+
+#[cfg(test)]
+thread_local! {
+    static CHECK_STORED_HASHES: Cell<bool> = const { Cell::new(true) };
+}
+
+// The checksum already covers the stored hashes, so checking them against
+// the strings is only worth the cost in debug builds. Tests can turn it off to
+// see that nothing else hashes the strings.
+#[cfg(not(test))]
+fn check_stored_hashes() -> bool {
+    cfg!(debug_assertions)
+}
+
+#[cfg(test)]
+fn check_stored_hashes() -> bool {
+    CHECK_STORED_HASHES.with(Cell::get)
+}
+
+// Parse `count` strings from `data`, calling `f` with the stored hash and the
+// string for each one.
+fn for_each_string<'a, F>(
+    mut data: &'a [u8],
+    count: u64,
+    mut f: F,
+) -> Result<(), SnapshotError>
+where
+    F: FnMut(u64, &'a str) -> Result<(), SnapshotError>,
+{
+    for _ in 0..count {
+        if data.len() < 16 {
+            return Err(SnapshotError::Truncated);
+        }
+        let hash = LittleEndian::read_u64(&data[..8]);
+        let len = LittleEndian::read_u64(&data[8..16]);
+        data = &data[16..];
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= data.len())
+            .ok_or(SnapshotError::Truncated)?;
+        let (bytes, rest) = data.split_at(len);
+        data = rest;
+        let s = std::str::from_utf8(bytes)
+            .map_err(|_| SnapshotError::InvalidUtf8)?;
+        f(hash, s)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        load, load_bins, stable_hash_bytes, write, write_bins, SnapshotError,
+        CHECK_STORED_HASHES, HEADER_LEN,
+    };
+    use crate::{
+        configure, existing_ustr, num_entries, string_cache_iter, ustr, Bins,
+        HashAlgorithm, HASH_CALLS,
+    };
+    use std::collections::HashSet;
+
+    #[test]
+    // We have to disable miri here as it's far too slow unfortunately
+    #[cfg_attr(miri, ignore)]
+    fn round_trip() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        let blns = include_str!("../data/blns.txt");
+        let expected: HashSet<&str> = blns.split_whitespace().collect();
+        for s in blns.split_whitespace() {
+            ustr(s);
+        }
+
+        let mut bytes = Vec::new();
+        write(&mut bytes).unwrap();
+
+        unsafe { crate::_clear_cache() };
+        assert_eq!(load(&mut bytes.as_slice()).unwrap(), expected.len());
+        assert_eq!(num_entries(), expected.len());
+        let found: HashSet<&str> = string_cache_iter().collect();
+        assert_eq!(found, expected);
+        for s in &expected {
+            let u = existing_ustr(s).unwrap();
+            assert_eq!(u.precomputed_hash(), ustr(s).precomputed_hash());
+        }
+
+        // Loading into a cache that already has the strings is fine too.
+        assert_eq!(load(&mut bytes.as_slice()).unwrap(), expected.len());
+        assert_eq!(num_entries(), expected.len());
+    }
+
+    #[test]
+    fn corrupt() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+        ustr("hello");
+        ustr("world");
+
+        let mut bytes = Vec::new();
+        write(&mut bytes).unwrap();
+        unsafe { crate::_clear_cache() };
+
+        let mut flipped = bytes.clone();
+        let last_string_byte = flipped.len() - 9;
+        flipped[last_string_byte] ^= 1;
+        assert!(matches!(
+            load(&mut flipped.as_slice()),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+
+        // A wrong hash with a checksum to match.
+        let mut bad_hash = bytes.clone();
+        bad_hash[HEADER_LEN] ^= 1;
+        let body_len = bad_hash.len() - 8;
+        let checksum = stable_hash_bytes(&bad_hash[..body_len]);
+        bad_hash[body_len..].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            load(&mut bad_hash.as_slice()),
+            Err(SnapshotError::HashMismatch)
+        ));
+
+        assert!(matches!(
+            load(&mut &bytes[..20]),
+            Err(SnapshotError::Truncated)
+        ));
+        assert!(matches!(
+            load(&mut &b"not a snapshot"[..]),
+            Err(SnapshotError::BadMagic)
+        ));
+
+        // Nothing was interned by the failed loads.
+        assert_eq!(num_entries(), 0);
+    }
+
+    #[test]
+    fn same_hash_algorithm() {
+        let words = ["alpha", "beta", "gamma"];
+        let written = Bins::new(&configure());
+        for s in words {
+            written.insert(s, written.hash(s)).unwrap();
+        }
+        let mut bytes = Vec::new();
+        write_bins(&written, &mut bytes).unwrap();
+
+        CHECK_STORED_HASHES.with(|check| check.set(false));
+        let bins = Bins::new(&configure());
+        HASH_CALLS.with(|calls| calls.set(0));
+        assert_eq!(load_bins(&bins, &mut bytes.as_slice()).unwrap(), 3);
+        // Only the check string is hashed.
+        assert_eq!(HASH_CALLS.with(|calls| calls.get()), 1);
+        CHECK_STORED_HASHES.with(|check| check.set(true));
+
+        for s in words {
+            assert!(bins.get_existing(s, written.hash(s)).is_some());
+        }
+    }
+
+    #[test]
+    fn other_hash_algorithm() {
+        let keyed = Bins::new(
+            &configure()
+                .hash_algorithm(HashAlgorithm::AHashKeyed([1, 2, 3, 4])),
+        );
+        let words = ["alpha", "beta", "gamma"];
+        for s in words {
+            keyed.insert(s, keyed.hash(s)).unwrap();
+        }
+        let mut bytes = Vec::new();
+        write_bins(&keyed, &mut bytes).unwrap();
+
+        // Different keys for the same algorithm give a different check hash,
+        // so the hashes are recomputed.
+        for algorithm in [
+            HashAlgorithm::AHash,
+            HashAlgorithm::AHashKeyed([4, 3, 2, 1]),
+        ] {
+            let bins = Bins::new(&configure().hash_algorithm(algorithm));
+            assert_eq!(load_bins(&bins, &mut bytes.as_slice()).unwrap(), 3);
+            for s in words {
+                let hash = bins.hash(s);
+                assert_ne!(hash, keyed.hash(s));
+                assert!(bins.get_existing(s, hash).is_some());
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use super::{Bins, STRING_CACHE};
+
+/// A snapshot of the state of a cache, for tuning its configuration and
+/// keeping an eye on its health.
+///
+/// Returned by [`stats()`] and [`Interner::stats()`](crate::Interner::stats).
+/// Everything is gathered in a single pass over the bins, each of which is
+/// locked in turn, so the totals are only exact if no other thread is
+/// interning strings at the same time.
+///
+/// # Examples
+///
+/// ```
+/// let stats = ustr::stats();
+/// assert_eq!(stats.bins.len(), 64);
+/// println!(
+///     "{} strings, {} bytes wasted, longest probe {}",
+///     stats.num_entries,
+///     stats.bytes_wasted,
+///     stats.max_probe_distance(),
+/// );
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct CacheStats {
+    /// Number of strings in the cache.
+    pub num_entries: usize,
+    /// Bytes of string storage in use, including the entry headers and
+    /// padding.
+    pub bytes_used: usize,
+    /// Bytes of string storage allocated.
+    pub bytes_reserved: usize,
+    /// Bytes at the ends of full allocators that are too small for the
+    /// strings that came after them, and will never be used.
+    pub bytes_wasted: usize,
+    /// Number of allocators that have filled up and been replaced.
+    pub num_old_allocs: usize,
+    /// Number of times a bin's table has grown.
+    pub num_grows: usize,
+    /// The number of strings found at each probe distance from the slot
+    /// their hash points to, so `probe_histogram[0]` is the number of strings
+    /// that are in their ideal slot. The last element is always non-zero.
+    pub probe_histogram: Vec<usize>,
+    /// Statistics for each bin.
+    pub bins: Vec<BinStats>,
+}
+
+/// Statistics for one bin (shard) of a cache, as part of [`CacheStats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct BinStats {
+    /// Number of strings in the bin.
+    pub num_entries: usize,
+    /// Number of slots in the bin's table.
+    pub capacity: usize,
+    /// Fraction of the table's slots that are in use.
+    pub load_factor: f64,
+    /// Bytes of string storage in use.
+    pub bytes_used: usize,
+    /// Bytes of string storage allocated.
+    pub bytes_reserved: usize,
+    /// Bytes at the ends of full allocators that will never be used.
+    pub bytes_wasted: usize,
+    /// Number of allocators that have filled up and been replaced.
+    pub num_old_allocs: usize,
+    /// Number of times the table has grown.
+    pub num_grows: usize,
+}
+
+impl CacheStats {
+    /// The longest probe distance of any string.
+    pub fn max_probe_distance(&self) -> usize {
+        self.probe_histogram.len().saturating_sub(1)
+    }
+
+    /// The mean probe distance over all the strings, or 0 if there are none.
+    pub fn mean_probe_distance(&self) -> f64 {
+        let (total, count) = self
+            .probe_histogram
+            .iter()
+            .enumerate()
+            .fold((0, 0), |(total, count), (distance, n)| {
+                (total + distance * n, count + n)
+            });
+        if count == 0 {
+            0.0
+        } else {
+            total as f64 / count as f64
+        }
+    }
+}
+
+/// Gather statistics about the global cache.
+///
+/// See [`CacheStats`].
+pub fn stats() -> CacheStats {
+    STRING_CACHE.stats()
+}
+
+impl Bins {
+    pub(crate) fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for m in self.bins.iter() {
+            let bin = m.lock().stats(&mut stats.probe_histogram);
+            stats.num_entries += bin.num_entries;
+            stats.bytes_used += bin.bytes_used;
+            stats.bytes_reserved += bin.bytes_reserved;
+            stats.bytes_wasted += bin.bytes_wasted;
+            stats.num_old_allocs += bin.num_old_allocs;
+            stats.num_grows += bin.num_grows;
+            stats.bins.push(bin);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{configure, Interner};
+
+    #[test]
+    fn interner_stats() {
+        let interner = Interner::with_config(
+            configure()
+                .num_bins(4)
+                .initial_capacity(16)
+                .initial_alloc(256),
+        )
+        .unwrap();
+        let stats = interner.stats();
+        assert_eq!(stats.num_entries, 0);
+        assert_eq!(stats.bins.len(), 4);
+        assert_eq!(stats.max_probe_distance(), 0);
+        assert_eq!(stats.mean_probe_distance(), 0.0);
+
+        let blns = include_str!("../data/blns.txt");
+        for s in blns.split_whitespace() {
+            interner.intern(s);
+        }
+        let stats = interner.stats();
+        assert_eq!(stats.num_entries, interner.len());
+        assert_eq!(stats.probe_histogram.iter().sum::<usize>(), interner.len());
+        assert_ne!(stats.probe_histogram.last(), Some(&0));
+        assert_eq!(stats.bytes_used, interner.total_allocated());
+        assert_eq!(stats.bytes_reserved, interner.total_capacity());
+        assert!(stats.bytes_used + stats.bytes_wasted <= stats.bytes_reserved);
+        assert!(stats.num_grows > 0);
+        assert!(stats.num_old_allocs > 0);
+        for bin in &stats.bins {
+            assert!(bin.capacity.is_power_of_two());
+            assert_eq!(
+                bin.load_factor,
+                bin.num_entries as f64 / bin.capacity as f64
+            );
+            // The default load factor is 0.5.
+            assert!(bin.load_factor <= 0.5);
+        }
+        assert_eq!(
+            stats.bins.iter().map(|b| b.num_entries).sum::<usize>(),
+            stats.num_entries
+        );
+    }
+}
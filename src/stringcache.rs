@@ -1,4 +1,18 @@
-use super::bumpalloc::LeakyBumpAlloc;
+use super::{
+    bumpalloc::LeakyBumpAlloc,
+    config::CacheConfig,
+    image::{ImageRegion, ImageSlot},
+    limits::InternError,
+    stats::BinStats,
+    Bins,
+};
+use parking_lot::{Mutex, MutexGuard};
+#[cfg(feature = "meta")]
+use std::sync::atomic::AtomicU64;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
 // `StringCache` stores a `Vec` of pointers to the `StringCacheEntry` structs.
 // The actual memory for the `StringCacheEntry` is stored in the LeakyBumpAlloc,
@@ -7,88 +21,106 @@ use super::bumpalloc::LeakyBumpAlloc;
 // have a 'static lifetime.
 //
 // The actual memory representation is as follows. Each `StringCacheEntry` is
-// aligned to 8 bytes on a 64-bit system. The 64-bit memoized hash of the string
-// is stored first, then a usize length, then the u8 characters, followed by a
-// null terminator (not included in len), then x<8 bytes of uninitialized memory
-// as padding before the next aligned entry.
+// aligned to 8 bytes on a 64-bit system. The 64-bit memoized hash of the
+// string is stored first, then a usize length, then the u8 characters,
+// followed by a null terminator (not included in len), then x<8 bytes of
+// uninitialized memory as padding before the next aligned entry. The length
+// always comes immediately before the characters and the hash immediately
+// before that.
 //
-//       hash             len       H e l l o , W o r l d !\0
-// |. . . . . . . .|. . . . . . . .|. . . . . . . .|. . . . . . . .|
-// 0               8               16                     len
-// ^ StringCacheEntry              ^ u8 chars               ^ null ^ Next entry
+// With the `ids` feature, the u32 id of the string and 4 bytes of padding go
+// first. With the `meta` feature, an atomic u64 of user metadata goes before
+// the hash, and with the `char-counts` feature the number of chars and UTF-16
+// code units in the string go after that, as two usizes. Each adds to the
+// size of the header.
+//
+//    hash             len       H e l l o , W o r l d !\0
+// |. . . . . . . .|. . . . . . . .|. . . . . . . .|. . . .
+// 0               8               16                    len
+// ^ StringCacheEntry              ^ u8 chars    ^ null ^ Next
 //
 // Proper alignment is guaranteed when allocating each entry as the alignment
 // is baked into the allocator. `StringCache` is responsible for monitoring the
 // Allocator and creating a new one when it would overflow -- the `Alloc` itself
-// will just `abort()` if it runs out of memory. Note that we abort() rather
-// than panic because the behaviour of the spinlock in case of a panic while
-// holding the lock is undefined.
+// will just refuse to allocate if it runs out of memory. Running out of memory
+// (or hitting the configured budget) while inserting is reported as an error
+// and leaves the cache as it was before the insert, so the bin can carry on
+// being used.
+//
+// Thread safety is ensured because we can only change the `StringCache` through
+// the lock in its `Bin`. The initial capacity of the cache is divided evenly
+// among a number of 'bins' or shards each with their own lock, in order to
+// reduce contention.
 //
-// Thread safety is ensured because we can only access the `StringCache` through
-// the spinlock in the `lazy_static` ref. The initial capacity of the cache is
-// divided evenly among a number of 'bins' or shards each with their own lock,
-// in order to reduce contention.
+// Looking up strings that are already in the cache doesn't need the lock
+// though. The table of entry pointers is made of atomics, and each bin
+// publishes a pointer to its current table. Entries are only ever added, and
+// the pointer to a new entry is stored in the table with release ordering
+// after the entry has been written, so a reader that sees the pointer sees
+// the whole entry. When the table grows, the new table is filled in before it
+// is published, and the old one is kept around until the cache is dropped in
+// case anyone is still reading it. Since each table is twice the size of the
+// last, the old ones take up less memory than the current one.
 #[repr(align(128))]
 pub(crate) struct StringCache {
     pub(crate) alloc: LeakyBumpAlloc,
     pub(crate) old_allocs: Vec<LeakyBumpAlloc>,
-    entries: Vec<*mut StringCacheEntry>,
+    // The current table, which is published by the `Bin` when the lock is
+    // released.
+    table: *mut Table,
+    // Tables we've grown out of that readers might still be using.
+    old_tables: Vec<*mut Table>,
     num_entries: usize,
-    mask: usize,
     total_allocated: usize,
+    // Number of times the table has grown.
+    num_grows: usize,
+    // Size of the first allocator, used when clearing the cache.
+    initial_alloc: usize,
+    // How much bigger each new allocator is than the last one.
+    growth_factor: f64,
+    // Fraction of the table we allow to fill up before growing it.
+    load_factor: f64,
+    // Grow the table once `num_entries` goes above this.
+    grow_threshold: usize,
     // Padding and aligning to 128 bytes gives up to 20% performance
     // improvement this actually aligns to 256 bytes because of the Mutex
     // around it.
     _pad: [u32; 3],
 }
 
-// TODO: make these configurable?
-// Initial size of the StringCache table
-pub(crate) const INITIAL_CAPACITY: usize = 1 << 20;
-// Initial size of the allocator storage (in bytes)
-pub(crate) const INITIAL_ALLOC: usize = 4 << 20;
-// Number of bins (shards) for map
-pub(crate) const BIN_SHIFT: usize = 6;
-pub(crate) const NUM_BINS: usize = 1 << BIN_SHIFT;
-// Shift for top bits to determine bin a hash falls into
-pub(crate) const TOP_SHIFT: usize =
-    8 * std::mem::size_of::<usize>() - BIN_SHIFT;
+// A table of pointers to entries, probed quadratically.
+pub(crate) struct Table {
+    mask: usize,
+    slots: Box<[AtomicPtr<StringCacheEntry>]>,
+}
 
-impl StringCache {
-    /// Create a new StringCache with the given starting capacity
-    pub fn new() -> StringCache {
-        let capacity = INITIAL_CAPACITY / NUM_BINS;
-        let alloc = LeakyBumpAlloc::new(
-            INITIAL_ALLOC / NUM_BINS,
-            std::mem::align_of::<StringCacheEntry>(),
-        );
-        StringCache {
-            // Current allocator.
-            alloc,
-            // Old allocators we'll keep around for iteration purposes.
-            // 16 would mean we've allocated 128GB of string storage since we
-            // double each time.
-            old_allocs: Vec::with_capacity(16),
-            // Vector of pointers to the `StringCacheEntry` headers.
-            entries: vec![std::ptr::null_mut(); capacity],
-            num_entries: 0,
+impl Table {
+    fn try_new(capacity: usize) -> Result<*mut Table, InternError> {
+        let mut slots = Vec::new();
+        slots
+            .try_reserve_exact(capacity)
+            .map_err(|_| InternError::OutOfMemory)?;
+        slots.resize_with(capacity, || AtomicPtr::new(std::ptr::null_mut()));
+        Ok(Box::into_raw(Box::new(Table {
             mask: capacity - 1,
-            total_allocated: capacity,
-            _pad: [0u32; 3],
-        }
+            slots: slots.into_boxed_slice(),
+        })))
     }
 
-    pub(crate) fn get_existing(
-        &self,
-        string: &str,
-        hash: u64,
-    ) -> Option<*const u8> {
+    // Look for the given string in the table. Returns a pointer to its chars
+    // if it's there, or the position of the empty slot where it would go if
+    // it isn't.
+    //
+    // This can be called concurrently with inserts into the table.
+    fn probe(&self, string: &[u8], hash: u64) -> Result<*const u8, usize> {
         let mut pos = self.mask & hash as usize;
         let mut dist = 0;
         loop {
-            let entry = unsafe { self.entries.get_unchecked(pos) };
+            // We know pos is in bounds as it's &ed with the mask.
+            let entry = unsafe { self.slots.get_unchecked(pos) }
+                .load(Ordering::Acquire);
             if entry.is_null() {
-                return None;
+                return Err(pos);
             }
             // This is safe as long as entry points to a valid address and the
             // layout described in the `StringCache` doc comment holds.
@@ -97,20 +129,19 @@ impl StringCache {
                 // pointer to the end of the entry, aka the beginning of the
                 // chars.
                 // As long as the memory is valid and the layout is correct,
-                // we're safe to create a string slice from the chars since
-                // they were copied directly from a valid `str`.
+                // we're safe to create a slice from the chars.
                 let entry_chars = entry.add(1) as *const u8;
                 // if entry is non-null then it must point to a valid
-                // StringCacheEntry
-                let sce = &**entry;
+                // StringCacheEntry, which the acquire load above makes sure
+                // we see all of
+                let sce = &*entry;
                 if sce.hash == hash
                     && sce.len == string.len()
-                    && std::str::from_utf8_unchecked(
-                        std::slice::from_raw_parts(entry_chars, sce.len),
-                    ) == string
+                    && std::slice::from_raw_parts(entry_chars, sce.len)
+                        == string
                 {
                     // found matching string in the cache already, return it
-                    return Some(entry_chars);
+                    return Ok(entry_chars);
                 }
             }
 
@@ -121,85 +152,218 @@ impl StringCache {
         }
     }
 
-    // Insert the given string with its given hash into the cache.
-    pub(crate) fn insert(&mut self, string: &str, hash: u64) -> *const u8 {
-        let mut pos = self.mask & hash as usize;
-        let mut dist = 0;
-        loop {
-            let entry = unsafe { self.entries.get_unchecked(pos) };
-            if entry.is_null() {
-                // found empty slot to insert
-                break;
-            }
+    // Put an entry into the empty slot at `pos`, making it visible to
+    // readers. The entry must be fully written.
+    fn set(&self, pos: usize, entry: *mut StringCacheEntry) {
+        self.slots[pos].store(entry, Ordering::Release);
+    }
+}
 
-            // This is safe as long as entry points to a valid address and the
-            // layout described in the `StringCache` doc comment holds.
-            unsafe {
-                // entry is a `*StringCacheEntry` so offseting by 1 gives us a
-                // pointer to the end of the entry, aka the beginning of the
-                // chars.
-                // As long as the memory is valid and the layout is correct,
-                // we're safe to create a string slice from the chars since
-                // they were copied directly from a valid `str`.
-                let entry_chars = entry.add(1) as *const u8;
-                // If entry is non-null then it must point to a valid
-                // `StringCacheEntry`.
-                let sce = &**entry;
-                if sce.hash == hash
-                    && sce.len == string.len()
-                    && std::str::from_utf8_unchecked(
-                        std::slice::from_raw_parts(entry_chars, sce.len),
-                    ) == string
-                {
-                    // found matching string in the cache already, return it
-                    return entry_chars;
-                }
-            }
+// One shard of a cache: a `StringCache` behind a lock, plus the pointer to its
+// current table for lookups that don't take the lock.
+pub(crate) struct Bin {
+    table: AtomicPtr<Table>,
+    cache: Mutex<StringCache>,
+}
 
-            // keep looking
-            dist += 1;
-            debug_assert!(dist <= self.mask);
-            pos = (pos + dist) & self.mask;
+impl Bin {
+    pub(crate) fn new(config: &CacheConfig) -> Bin {
+        let cache = StringCache::new(config);
+        Bin {
+            table: AtomicPtr::new(cache.table),
+            cache: Mutex::new(cache),
+        }
+    }
+
+    // Lock the bin for changing its `StringCache`.
+    pub(crate) fn lock(&self) -> BinGuard<'_> {
+        BinGuard {
+            bin: self,
+            cache: self.cache.lock(),
+        }
+    }
+
+    // Look up a string without taking the lock.
+    #[inline]
+    pub(crate) fn get_existing(
+        &self,
+        string: &[u8],
+        hash: u64,
+    ) -> Option<*const u8> {
+        let table = self.table.load(Ordering::Acquire);
+        // This is safe as tables are never freed while the cache is alive.
+        unsafe { (*table).probe(string, hash).ok() }
+    }
+}
+
+// Publishes the `StringCache`'s current table when the lock is released, in
+// case it grew.
+pub(crate) struct BinGuard<'a> {
+    bin: &'a Bin,
+    cache: MutexGuard<'a, StringCache>,
+}
+
+impl Deref for BinGuard<'_> {
+    type Target = StringCache;
+    fn deref(&self) -> &StringCache {
+        &self.cache
+    }
+}
+
+impl DerefMut for BinGuard<'_> {
+    fn deref_mut(&mut self) -> &mut StringCache {
+        &mut self.cache
+    }
+}
+
+impl Drop for BinGuard<'_> {
+    fn drop(&mut self) {
+        if self.bin.table.load(Ordering::Relaxed) != self.cache.table {
+            self.bin.table.store(self.cache.table, Ordering::Release);
+        }
+    }
+}
+
+// Defaults for `CacheConfig`, which can override them at runtime.
+// Initial size of the StringCache table
+pub(crate) const INITIAL_CAPACITY: usize = 1 << 20;
+// Initial size of the allocator storage (in bytes)
+pub(crate) const INITIAL_ALLOC: usize = 4 << 20;
+// Number of bins (shards) for map
+pub(crate) const BIN_SHIFT: usize = 6;
+pub(crate) const NUM_BINS: usize = 1 << BIN_SHIFT;
+// Geometry of the smaller global caches for byte strings, paths and lists,
+// which usually hold far fewer records than the string cache, and of a new
+// `Interner`.
+pub(crate) const SECONDARY_CAPACITY: usize = 1 << 12;
+pub(crate) const SECONDARY_ALLOC: usize = 64 << 10;
+pub(crate) const SECONDARY_NUM_BINS: usize = 4;
+
+impl StringCache {
+    /// Create a new StringCache for one bin of a cache with the given
+    /// configuration.
+    pub fn new(config: &CacheConfig) -> StringCache {
+        let capacity = config.bin_capacity();
+        let initial_alloc = config.bin_alloc();
+        let alloc = LeakyBumpAlloc::new(
+            initial_alloc,
+            std::mem::align_of::<StringCacheEntry>(),
+        );
+        StringCache {
+            // Current allocator.
+            alloc,
+            // Old allocators we'll keep around for iteration purposes.
+            // 16 would mean we've allocated 128GB of string storage since we
+            // double each time.
+            old_allocs: Vec::with_capacity(16),
+            // Table of pointers to the `StringCacheEntry` headers.
+            table: Table::try_new(capacity).expect("oom"),
+            old_tables: Vec::new(),
+            num_entries: 0,
+            total_allocated: capacity,
+            num_grows: 0,
+            initial_alloc,
+            growth_factor: config.growth_factor,
+            load_factor: config.load_factor,
+            grow_threshold: grow_threshold(capacity - 1, config.load_factor),
+            _pad: [0u32; 3],
         }
+    }
 
-        //
-        // Insert the new string.
-        //
+    fn table(&self) -> &Table {
+        // This is safe as the table lives as long as we do.
+        unsafe { &*self.table }
+    }
+
+    fn probe(&self, string: &[u8], hash: u64) -> Result<*const u8, usize> {
+        self.table().probe(string, hash)
+    }
+
+    // Insert the given string with its given hash into the cache.
+    //
+    // If this returns an error then nothing has been inserted and the cache
+    // is still in a consistent state, so it's safe to keep using it.
+    pub(crate) fn insert(
+        &mut self,
+        string: &[u8],
+        hash: u64,
+        bins: &Bins,
+    ) -> Result<*const u8, InternError> {
+        let pos = match self.probe(string, hash) {
+            Ok(entry_chars) => return Ok(entry_chars),
+            Err(pos) => pos,
+        };
+
+        // Strings that are already in the cache are always handed back, but
+        // new ones have to fit within the limits.
+        bins.check_len(string.len())?;
+        bins.limits.reserve_entry()?;
+        let result = self.insert_new(string, hash, pos, bins);
+        if result.is_err() {
+            bins.limits.release_entry();
+        }
+        result
+    }
 
-        // We know pos is in bounds as it's &ed with the mask above.
-        let entry_ptr = unsafe { self.entries.get_unchecked_mut(pos) };
-        // Ddd one to length for null byte.
-        // There's no way we could overflow here in practice since that would
-        // require having allocated a `u64::MAX`-length string, by which time
-        // we'll be using 128-bit pointers and we'll need to rewrite this
-        // crate anyway.
-        let byte_len = string.len() + 1;
-        let alloc_size = std::mem::size_of::<StringCacheEntry>() + byte_len;
+    // Insert a string that isn't in the cache yet, where `pos` is the empty
+    // slot `probe()` found for it.
+    fn insert_new(
+        &mut self,
+        string: &[u8],
+        hash: u64,
+        mut pos: usize,
+        bins: &Bins,
+    ) -> Result<*const u8, InternError> {
+        // Add one to length for null byte.
+        let byte_len = string
+            .len()
+            .checked_add(1)
+            .ok_or(InternError::OutOfMemory)?;
+        let alloc_size = std::mem::size_of::<StringCacheEntry>()
+            .checked_add(byte_len)
+            .ok_or(InternError::OutOfMemory)?;
+        // The allocator rounds every allocation to the entry alignment.
+        let align = std::mem::align_of::<StringCacheEntry>();
+        let alloc_size =
+            round_up_to(alloc_size, align).ok_or(InternError::OutOfMemory)?;
+
+        // We want to keep the configured load factor for the map, so grow if
+        // this entry would take us over it. We do this before allocating the
+        // entry so that a failure leaves nothing half-inserted.
+        if self.num_entries + 1 > self.grow_threshold {
+            unsafe { self.grow()? };
+            pos = match self.probe(string, hash) {
+                Ok(_) => unreachable!("string appeared while growing"),
+                Err(pos) => pos,
+            };
+        }
 
         // if our new allocation would spill over the allocator, make a new
         // allocator and let the old one leak
         let capacity = self.alloc.capacity();
         let allocated = self.alloc.allocated();
-        if alloc_size
-            .checked_add(allocated)
-            .expect("overflowed alloc_size + allocated")
-            > capacity
-        {
-            let new_capacity = capacity
-                .checked_mul(2)
-                .expect("capacity * 2 overflowed")
+        if alloc_size + allocated > capacity {
+            let desired = grown_capacity(capacity, self.growth_factor)
+                .and_then(|c| round_up_to(c, align))
+                .unwrap_or(usize::MAX & !(align - 1))
                 .max(alloc_size);
-            let old_alloc = std::mem::replace(
-                &mut self.alloc,
-                LeakyBumpAlloc::new(
-                    new_capacity,
-                    std::mem::align_of::<StringCacheEntry>(),
-                ),
-            );
+            let new_capacity =
+                bins.limits.reserve_bytes(alloc_size, desired, align)?;
+            let new_alloc = match LeakyBumpAlloc::try_new(new_capacity, align) {
+                Some(alloc) => alloc,
+                None => {
+                    bins.limits.release_bytes(new_capacity);
+                    return Err(InternError::OutOfMemory);
+                }
+            };
+            let old_alloc = std::mem::replace(&mut self.alloc, new_alloc);
             self.old_allocs.push(old_alloc);
             self.total_allocated += new_capacity;
         }
 
+        #[cfg(feature = "char-counts")]
+        let (char_count, utf16_len) = char_counts(string);
+
         // This is safe as long as:
         // 1. `alloc_size` is calculated correctly.
         // 2. there is enough space in the allocator (checked in the block
@@ -207,15 +371,35 @@ impl StringCache {
         // 3. The `StringCacheEntry` layout descibed above holds and the memory
         //    returned by allocate() is prooperly aligned.
         unsafe {
-            *entry_ptr =
-                self.alloc.allocate(alloc_size) as *mut StringCacheEntry;
+            let entry_ptr = self
+                .alloc
+                .allocate(alloc_size)
+                .ok_or(InternError::OutOfMemory)?
+                as *mut StringCacheEntry;
+            // Only take an id once we have somewhere to put the string, and
+            // hand the space back if we can't get one, since the allocator is
+            // walked when iterating over the strings.
+            #[cfg(feature = "ids")]
+            let id = match bins.ids.reserve() {
+                Ok(id) => id,
+                Err(e) => {
+                    self.alloc.unallocate(entry_ptr as *mut u8, alloc_size);
+                    return Err(e);
+                }
+            };
 
             // Write the header.
-            // `entry_ptr` is guaranteed to point to a valid `StringCacheEntry`,
-            // or `alloc.allocate()` would have aborted.
             std::ptr::write(
-                *entry_ptr,
+                entry_ptr,
                 StringCacheEntry {
+                    #[cfg(feature = "ids")]
+                    id: id.get(),
+                    #[cfg(feature = "meta")]
+                    meta: AtomicU64::new(0),
+                    #[cfg(feature = "char-counts")]
+                    char_count,
+                    #[cfg(feature = "char-counts")]
+                    utf16_len,
                     hash,
                     len: string.len(),
                 },
@@ -223,7 +407,7 @@ impl StringCache {
             // Write the characters after the `StringCacheEntry`.
             let char_ptr = entry_ptr.add(1) as *mut u8;
             std::ptr::copy_nonoverlapping(
-                string.as_bytes().as_ptr(),
+                string.as_ptr(),
                 char_ptr,
                 string.len(),
             );
@@ -231,15 +415,77 @@ impl StringCache {
             let write_ptr = char_ptr.add(string.len());
             std::ptr::write(write_ptr, 0u8);
 
+            self.table().set(pos, entry_ptr);
             self.num_entries += 1;
-            // We want to keep an 0.5 load factor for the map, so grow if we've
-            // exceeded that.
-            if self.num_entries * 2 > self.mask {
-                self.grow();
-            }
+            #[cfg(feature = "ids")]
+            bins.ids.publish(id, entry_ptr);
+
+            Ok(char_ptr)
+        }
+    }
+
+    // Link an entry that lives in memory owned by someone else, such as a
+    // mapped cache image, into the table without copying it or writing to it.
+    // The entry's hash must be the hash of its chars under the cache's hash
+    // function. Its id, if it gets one, goes in `slot`, which is filled in
+    // before the entry can be found since the entry can't hold it.
+    //
+    // Returns false if the string was already in the cache.
+    //
+    // This is safe as long as `entry` points to a valid entry with the
+    // layout described above that lives for as long as the cache.
+    pub(crate) unsafe fn link(
+        &mut self,
+        entry: *const StringCacheEntry,
+        slot: &ImageSlot,
+        bins: &Bins,
+    ) -> Result<bool, InternError> {
+        let string = (*entry).as_bytes();
+        let hash = (*entry).hash;
+        let pos = match self.probe(string, hash) {
+            Ok(_) => return Ok(false),
+            Err(pos) => pos,
+        };
+
+        bins.check_len(string.len())?;
+        bins.limits.reserve_entry()?;
+        let result = self.link_new(entry, pos, slot, bins);
+        if result.is_err() {
+            bins.limits.release_entry();
+        }
+        result.map(|()| true)
+    }
 
-            char_ptr
+    // Link an entry that isn't in the cache yet, where `pos` is the empty
+    // slot `probe()` found for it.
+    #[cfg_attr(not(feature = "ids"), allow(unused_variables))]
+    unsafe fn link_new(
+        &mut self,
+        entry: *const StringCacheEntry,
+        mut pos: usize,
+        slot: &ImageSlot,
+        bins: &Bins,
+    ) -> Result<(), InternError> {
+        if self.num_entries + 1 > self.grow_threshold {
+            self.grow()?;
+            pos = match self.probe((*entry).as_bytes(), (*entry).hash) {
+                Ok(_) => unreachable!("string appeared while growing"),
+                Err(pos) => pos,
+            };
         }
+        #[cfg(feature = "ids")]
+        let id = bins.ids.reserve()?;
+
+        #[cfg(feature = "ids")]
+        slot.id.store(id.get(), Ordering::Relaxed);
+        slot.linked.store(true, Ordering::Relaxed);
+        // The table only ever reads through its entry pointers.
+        let entry = entry as *mut StringCacheEntry;
+        self.table().set(pos, entry);
+        self.num_entries += 1;
+        #[cfg(feature = "ids")]
+        bins.ids.publish(id, entry);
+        Ok(())
     }
 
     // Double the size of the map storage.
@@ -247,26 +493,33 @@ impl StringCache {
     // This is safe as long as:
     // - The in-memory layout of the `StringCacheEntry` is correct.
     //
-    // If there's not enough memory for the new entry table, it will just abort
-    pub(crate) unsafe fn grow(&mut self) {
-        let new_mask = self.mask * 2 + 1;
-
-        let mut new_entries: std::vec::Vec<*mut StringCacheEntry> =
-            vec![std::ptr::null_mut(); new_mask + 1];
+    // If there's not enough memory for the new entry table, the old one is
+    // left as it was.
+    pub(crate) unsafe fn grow(&mut self) -> Result<(), InternError> {
+        self.old_tables
+            .try_reserve(1)
+            .map_err(|_| InternError::OutOfMemory)?;
+        let old_table = &*self.table;
+        let new_mask = old_table.mask * 2 + 1;
+        let new_table = Table::try_new(new_mask + 1)?;
+        let new_slots = &(*new_table).slots;
 
         // copy the existing map into the new map
         let mut to_copy = self.num_entries;
-        for e in self.entries.iter_mut() {
+        for e in old_table.slots.iter() {
+            if to_copy == 0 {
+                break;
+            }
+            let e = e.load(Ordering::Relaxed);
             if e.is_null() {
                 continue;
             }
 
-            // Start of the entry is the hash.
-            let hash = *(*e as *const u64);
+            let hash = (*e).hash;
             let mut pos = (hash as usize) & new_mask;
             let mut dist = 0;
             loop {
-                if new_entries[pos].is_null() {
+                if new_slots[pos].load(Ordering::Relaxed).is_null() {
                     // Here's an empty slot to put the pointer in.
                     break;
                 }
@@ -278,22 +531,26 @@ impl StringCache {
                 pos = pos.wrapping_add(dist) & new_mask;
             }
 
-            new_entries[pos] = *e;
+            // Nobody else can see the new table until it's published.
+            new_slots[pos].store(e, Ordering::Relaxed);
             to_copy -= 1;
-            if to_copy == 0 {
-                break;
-            }
         }
 
-        self.entries = new_entries;
-        self.mask = new_mask;
+        // Readers might still be using the old table, so keep it around.
+        self.old_tables.push(self.table);
+        self.table = new_table;
+        self.num_grows += 1;
+        self.grow_threshold = grow_threshold(new_mask, self.load_factor);
+        Ok(())
     }
 
     // This is only called by `clear()` during tests to clear the cache between
     // runs. **DO NOT CALL THIS**.
     pub(crate) unsafe fn clear(&mut self) {
         // just zero all the pointers that have already been set
-        std::ptr::write_bytes(self.entries.as_mut_ptr(), 0, self.mask + 1);
+        for slot in self.table().slots.iter() {
+            slot.store(std::ptr::null_mut(), Ordering::Relaxed);
+        }
         self.num_entries = 0;
         self.total_allocated = 0;
         for a in self.old_allocs.iter_mut() {
@@ -302,7 +559,7 @@ impl StringCache {
         self.old_allocs = Vec::new();
         self.alloc.clear();
         self.alloc = LeakyBumpAlloc::new(
-            INITIAL_ALLOC / NUM_BINS,
+            self.initial_alloc,
             std::mem::align_of::<StringCacheEntry>(),
         );
     }
@@ -320,77 +577,201 @@ impl StringCache {
     pub(crate) fn num_entries(&self) -> usize {
         self.num_entries
     }
+
+    // Gather statistics for this bin, adding the probe distance of each entry
+    // to `probe_histogram`.
+    pub(crate) fn stats(&self, probe_histogram: &mut Vec<usize>) -> BinStats {
+        let table = self.table();
+        for (pos, slot) in table.slots.iter().enumerate() {
+            let entry = slot.load(Ordering::Relaxed);
+            if entry.is_null() {
+                continue;
+            }
+            // Retrace the probe sequence from the entry's home slot.
+            let hash = unsafe { (*entry).hash };
+            let mut probe = table.mask & hash as usize;
+            let mut dist = 0;
+            while probe != pos {
+                dist += 1;
+                probe = (probe + dist) & table.mask;
+            }
+            if probe_histogram.len() <= dist {
+                probe_histogram.resize(dist + 1, 0);
+            }
+            probe_histogram[dist] += 1;
+        }
+
+        let capacity = table.mask + 1;
+        BinStats {
+            num_entries: self.num_entries,
+            capacity,
+            load_factor: self.num_entries as f64 / capacity as f64,
+            bytes_used: self.total_allocated(),
+            bytes_reserved: self.total_capacity(),
+            bytes_wasted: self
+                .old_allocs
+                .iter()
+                .map(|a| a.capacity() - a.allocated())
+                .sum(),
+            num_old_allocs: self.old_allocs.len(),
+            num_grows: self.num_grows,
+        }
+    }
+}
+
+// The global cache lives in a static so is never dropped, but an `Interner`
+// owns its caches and gives all their memory back when it goes away.
+impl Drop for StringCache {
+    fn drop(&mut self) {
+        // This is safe as nothing can still be pointing into the allocators
+        // or tables: handles to the strings borrow the cache that owns them.
+        unsafe {
+            for a in self.old_allocs.iter_mut() {
+                a.clear();
+            }
+            self.alloc.clear();
+            for t in self.old_tables.drain(..) {
+                drop(Box::from_raw(t));
+            }
+            drop(Box::from_raw(self.table));
+        }
+    }
 }
 
 impl Default for StringCache {
     fn default() -> StringCache {
-        StringCache::new()
+        StringCache::new(&CacheConfig::default())
+    }
+}
+
+// Number of entries a table with the given mask can hold before it needs to
+// grow. This is always less than the number of slots so probing terminates.
+fn grow_threshold(mask: usize, load_factor: f64) -> usize {
+    (mask as f64 * load_factor) as usize
+}
+
+// Capacity of the next allocator after one of the given capacity fills up.
+fn grown_capacity(capacity: usize, growth_factor: f64) -> Option<usize> {
+    let new_capacity = (capacity as f64 * growth_factor).ceil();
+    if new_capacity >= usize::MAX as f64 {
+        None
+    } else {
+        Some(new_capacity as usize)
     }
 }
 
 // We are safe to be `Send` but not `Sync` (we get Sync by wrapping in a mutex).
 unsafe impl Send for StringCache {}
 
+// The table pointer is only used to read the table, which is made of atomics.
+unsafe impl Send for Bin {}
+unsafe impl Sync for Bin {}
+
 #[doc(hidden)]
+#[derive(Clone)]
 pub struct StringCacheIterator {
     pub(crate) allocs: Vec<(*const u8, *const u8)>,
     pub(crate) current_alloc: usize,
     pub(crate) current_ptr: *const u8,
+    // Registered cache images, whose strings come after the ones in the
+    // allocators.
+    pub(crate) images: Vec<&'static ImageRegion>,
+    pub(crate) current_image: usize,
+    pub(crate) current_entry: usize,
 }
 
-fn round_up_to(n: usize, align: usize) -> usize {
+pub(crate) fn round_up_to(n: usize, align: usize) -> Option<usize> {
     debug_assert!(align.is_power_of_two());
-    (n.checked_add(align).expect("round_up_to overflowed") - 1) & !(align - 1)
+    Some((n.checked_add(align)? - 1) & !(align - 1))
 }
 
-impl Iterator for StringCacheIterator {
-    type Item = &'static str;
-    fn next(&mut self) -> Option<Self::Item> {
+impl StringCacheIterator {
+    fn next_in_allocs(&mut self) -> Option<&'static str> {
         // check that the cache is not empty before accessing
         if self.allocs.is_empty() {
             return None;
         }
 
-        let (_, end) = self.allocs[self.current_alloc];
-        if self.current_ptr >= end {
-            // We've reached the end of the current alloc.
-            if self.current_alloc == self.allocs.len() - 1 {
-                // We've reached the end.
-                return None;
-            } else {
-                // Advance to the next alloc.
-                self.current_alloc += 1;
-                let (current_ptr, _) = self.allocs[self.current_alloc];
-                self.current_ptr = current_ptr;
+        loop {
+            let (_, end) = self.allocs[self.current_alloc];
+            if self.current_ptr >= end {
+                // We've reached the end of the current alloc.
+                if self.current_alloc == self.allocs.len() - 1 {
+                    // We've reached the end.
+                    return None;
+                } else {
+                    // Advance to the next alloc.
+                    self.current_alloc += 1;
+                    let (current_ptr, _) = self.allocs[self.current_alloc];
+                    self.current_ptr = current_ptr;
+                    continue;
+                }
+            }
+
+            // Cast the current ptr to a `StringCacheEntry` and create the next
+            // string from it.
+            unsafe {
+                let sce = &*(self.current_ptr as *const StringCacheEntry);
+                // The next entry will be the size of the number of bytes in
+                // the string, +1 for the null byte, rounded up to the
+                // alignment (8).
+                self.current_ptr = sce.next_entry();
+
+                return Some(sce.as_str());
             }
         }
+    }
 
-        // Cast the current ptr to a `StringCacheEntry` and create the next
-        // string from it.
-        unsafe {
-            let sce = &*(self.current_ptr as *const StringCacheEntry);
-            // The next entry will be the size of the number of bytes in the
-            // string, +1 for the null byte, rounded up to the alignment (8).
-            self.current_ptr = sce.next_entry();
-
-            // We know we're safe not to check here since we put valid UTF-8 in.
-            let s = std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-                sce.char_ptr(),
-                sce.len,
-            ));
-            Some(s)
+    fn next_in_images(&mut self) -> Option<&'static str> {
+        while let Some(image) = self.images.get(self.current_image) {
+            if self.current_entry == image.len() {
+                self.current_image += 1;
+                self.current_entry = 0;
+                continue;
+            }
+            let index = self.current_entry;
+            self.current_entry += 1;
+            // Entries in cache images that duplicate a string that was
+            // already in the cache are never linked in.
+            if let Some(s) = image.linked_str(index) {
+                return Some(s);
+            }
         }
+        None
+    }
+}
+
+impl Iterator for StringCacheIterator {
+    type Item = &'static str;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_in_allocs().or_else(|| self.next_in_images())
     }
 }
 
 #[repr(C)]
-#[derive(Clone)]
 pub(crate) struct StringCacheEntry {
+    #[cfg(feature = "ids")]
+    pub(crate) id: u32,
+    #[cfg(feature = "meta")]
+    pub(crate) meta: AtomicU64,
+    #[cfg(feature = "char-counts")]
+    pub(crate) char_count: usize,
+    #[cfg(feature = "char-counts")]
+    pub(crate) utf16_len: usize,
     pub(crate) hash: u64,
     pub(crate) len: usize,
 }
 
 impl StringCacheEntry {
+    // Get the entry from the pointer to its characters. The pointer must have
+    // come from a `StringCacheEntry` that lives for 'a.
+    #[inline]
+    pub(crate) unsafe fn from_char_ptr<'a>(
+        char_ptr: *const u8,
+    ) -> &'a StringCacheEntry {
+        &*(char_ptr as *const StringCacheEntry).sub(1)
+    }
+
     // Get the pointer to the characters.
     pub(crate) fn char_ptr(&self) -> *const u8 {
         // We know the chars are always directly after this struct in memory
@@ -398,13 +779,92 @@ impl StringCacheEntry {
         unsafe { (self as *const StringCacheEntry).add(1) as *const u8 }
     }
 
+    // Get the chars as a byte slice.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.char_ptr(), self.len) }
+    }
+
+    // Get the chars as a `str`.
+    pub(crate) fn as_str(&self) -> &str {
+        // We know we're safe not to check here since we put valid UTF-8 in.
+        unsafe {
+            std::str::from_utf8_unchecked(std::slice::from_raw_parts(
+                self.char_ptr(),
+                self.len,
+            ))
+        }
+    }
+
     // Calcualte the address of the next entry in the cache. This is a utility
     // function to hide the pointer arithmetic in iterators.
     pub(crate) unsafe fn next_entry(&self) -> *const u8 {
         #[allow(clippy::ptr_offset_with_cast)]
-        self.char_ptr().add(round_up_to(
-            self.len + 1,
-            std::mem::align_of::<StringCacheEntry>(),
-        ))
+        self.char_ptr().add(
+            round_up_to(self.len + 1, std::mem::align_of::<StringCacheEntry>())
+                .expect("round_up_to overflowed"),
+        )
+    }
+}
+
+// Count the chars in a UTF-8 string and the UTF-16 code units it would take,
+// without decoding it. Every char has exactly one byte that isn't a
+// continuation byte, and the chars that take four bytes are the ones that
+// take two UTF-16 code units.
+//
+// Other caches use the same entries for bytes that aren't UTF-8, for which
+// the counts don't mean anything, but they're never looked at.
+#[cfg(feature = "char-counts")]
+pub(crate) fn char_counts(bytes: &[u8]) -> (usize, usize) {
+    let chars = bytes.iter().filter(|&&b| (b as i8) >= -0x40).count();
+    let pairs = bytes.iter().filter(|&&b| b >= 0xf0).count();
+    (chars, chars + pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{configure, Interner};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn lookups_while_growing() {
+        // A tiny table in a single bin grows many times while the readers are
+        // looking strings up in it.
+        let interner = Interner::with_config(
+            configure()
+                .num_bins(1)
+                .initial_capacity(2)
+                .initial_alloc(64),
+        )
+        .unwrap();
+        let strings: Vec<String> =
+            (0..20_000).map(|n| format!("string {}", n)).collect();
+        // Number of strings the writer has finished interning.
+        let done = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let (interner, strings, done) = (&interner, &strings, &done);
+                scope.spawn(move || loop {
+                    let n = done.load(Ordering::Acquire);
+                    // Check some of the strings that must be there.
+                    for s in strings[..n].iter().rev().skip(t).step_by(97) {
+                        let found =
+                            interner.get(s).expect("string went missing");
+                        assert_eq!(found.as_str(), s);
+                    }
+                    if n == strings.len() {
+                        break;
+                    }
+                });
+            }
+
+            for (n, s) in strings.iter().enumerate() {
+                interner.intern(s);
+                done.store(n + 1, Ordering::Release);
+            }
+        });
+
+        assert_eq!(interner.len(), strings.len());
     }
-}
\ No newline at end of file
+}
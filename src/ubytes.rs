@@ -0,0 +1,386 @@
+use super::{new_global_cache, Bins, InternError, StringCacheEntry, Ustr};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    ffi::CStr,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    ptr::NonNull,
+    slice, str,
+};
+
+/// A handle representing a byte string in the global byte string cache.
+///
+/// This is the same as a [`Ustr`], except that it can hold any bytes, not
+/// just valid UTF-8, which makes it useful for things like file names and
+/// tokens from binary protocols. Byte strings are stored in their own cache,
+/// separate from the one for `Ustr`s, with the same layout: each is followed
+/// by a null terminator and comes with its precomputed hash. The memory
+/// budget and entry limit set with
+/// [`CacheConfig::init`](crate::CacheConfig::init) are shared by both caches,
+/// and the maximum string length applies to byte strings too.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ubytes, UBytesSet};
+///
+/// let name = ubytes(b"caf\xe9.txt");
+/// assert_eq!(name, ubytes(b"caf\xe9.txt"));
+/// assert_eq!(name.as_bytes(), b"caf\xe9.txt");
+/// assert!(name.to_str().is_err());
+///
+/// let mut seen = UBytesSet::default();
+/// seen.insert(name);
+/// assert!(seen.contains(&ubytes(b"caf\xe9.txt")));
+/// ```
+#[derive(Copy, Clone, PartialEq)]
+#[repr(transparent)]
+pub struct UBytes {
+    char_ptr: NonNull<u8>,
+}
+
+/// Defer to `[u8]` for ordering.
+impl Ord for UBytes {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+#[allow(clippy::non_canonical_partial_ord_impl)]
+impl PartialOrd for UBytes {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl UBytes {
+    /// Create a new `UBytes` from the given bytes.
+    ///
+    /// You can also use the [`ubytes`] function.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bytes can't be interned, either because we ran out of
+    /// memory or because one of the configured limits was reached. Use
+    /// [`UBytes::try_from`] to handle that instead.
+    pub fn from(bytes: &[u8]) -> UBytes {
+        UBytes::try_from(bytes)
+            .unwrap_or_else(|e| panic!("failed to intern bytes: {}", e))
+    }
+
+    /// Create a new `UBytes` from the given bytes, returning an error rather
+    /// than panicking if they can't be stored.
+    pub fn try_from(bytes: &[u8]) -> Result<UBytes, InternError> {
+        let hash = BYTES_CACHE.hash_bytes(bytes);
+        let ptr = BYTES_CACHE.insert_bytes(bytes, hash)?;
+        Ok(UBytes {
+            // SAFETY: insert does not give back a null pointer
+            char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        })
+    }
+
+    /// Get the `UBytes` for the given bytes, but only if they have already
+    /// been interned. This never takes a lock.
+    pub fn from_existing(bytes: &[u8]) -> Option<UBytes> {
+        let hash = BYTES_CACHE.hash_bytes(bytes);
+        BYTES_CACHE
+            .get_existing_bytes(bytes, hash)
+            .map(|ptr| UBytes {
+                char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+            })
+    }
+
+    /// Get the cached bytes.
+    pub fn as_bytes(&self) -> &'static [u8] {
+        // This is safe as the bytes were copied from a valid slice of this
+        // length and are never freed.
+        unsafe { slice::from_raw_parts(self.char_ptr.as_ptr(), self.len()) }
+    }
+
+    /// Get a raw pointer to the bytes, which are followed by a null
+    /// terminator.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.char_ptr.as_ptr()
+    }
+
+    /// Get the bytes as a [`CStr`], or `None` if they contain a null byte.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::ubytes;
+    ///
+    /// assert_eq!(
+    ///     ubytes(b"\xffname").as_cstr().unwrap().to_bytes(),
+    ///     b"\xffname"
+    /// );
+    /// assert!(ubytes(b"a\0b").as_cstr().is_none());
+    /// ```
+    pub fn as_cstr(&self) -> Option<&'static CStr> {
+        // The null terminator is always there after the bytes.
+        let with_nul = unsafe {
+            slice::from_raw_parts(self.char_ptr.as_ptr(), self.len() + 1)
+        };
+        CStr::from_bytes_with_nul(with_nul).ok()
+    }
+
+    /// Get the `Ustr` for these bytes if they are valid UTF-8.
+    ///
+    /// This interns the string in the global string cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::{ubytes, ustr};
+    ///
+    /// assert_eq!(ubytes(b"hello").to_str().unwrap(), ustr("hello"));
+    /// assert!(ubytes(b"\xff").to_str().is_err());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be interned, like [`Ustr::from`].
+    pub fn to_str(&self) -> Result<Ustr, str::Utf8Error> {
+        str::from_utf8(self.as_bytes()).map(Ustr::from)
+    }
+
+    /// Get the bytes as a `str`, replacing any invalid UTF-8 with
+    /// U+FFFD REPLACEMENT CHARACTER.
+    pub fn to_string_lossy(&self) -> Cow<'static, str> {
+        String::from_utf8_lossy(self.as_bytes())
+    }
+
+    #[inline]
+    fn as_string_cache_entry(&self) -> &StringCacheEntry {
+        // The allocator guarantees that the alignment is correct and that
+        // this pointer is non-null
+        unsafe { StringCacheEntry::from_char_ptr(self.char_ptr.as_ptr()) }
+    }
+
+    /// Get the length in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.as_string_cache_entry().len
+    }
+
+    /// Returns true if the length is zero.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the precomputed hash for these bytes.
+    ///
+    /// Byte strings that are valid UTF-8 have the same hash as the matching
+    /// `Ustr`.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        self.as_string_cache_entry().hash
+    }
+}
+
+// We're safe to impl these for the same reasons as for `Ustr`.
+unsafe impl Send for UBytes {}
+unsafe impl Sync for UBytes {}
+
+impl Eq for UBytes {}
+
+impl PartialEq<[u8]> for UBytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_bytes() == other
+    }
+}
+
+impl PartialEq<&[u8]> for UBytes {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self.as_bytes() == *other
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for UBytes {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self.as_bytes() == other
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8; N]> for UBytes {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        self.as_bytes() == *other
+    }
+}
+
+impl PartialEq<Vec<u8>> for UBytes {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.as_bytes() == other.as_slice()
+    }
+}
+
+impl PartialEq<UBytes> for [u8] {
+    fn eq(&self, other: &UBytes) -> bool {
+        self == other.as_bytes()
+    }
+}
+
+impl PartialEq<UBytes> for &[u8] {
+    fn eq(&self, other: &UBytes) -> bool {
+        *self == other.as_bytes()
+    }
+}
+
+impl PartialEq<UBytes> for Vec<u8> {
+    fn eq(&self, other: &UBytes) -> bool {
+        self.as_slice() == other.as_bytes()
+    }
+}
+
+impl AsRef<[u8]> for UBytes {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl From<&[u8]> for UBytes {
+    fn from(bytes: &[u8]) -> UBytes {
+        UBytes::from(bytes)
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for UBytes {
+    fn from(bytes: &[u8; N]) -> UBytes {
+        UBytes::from(bytes)
+    }
+}
+
+impl From<Vec<u8>> for UBytes {
+    fn from(bytes: Vec<u8>) -> UBytes {
+        UBytes::from(&bytes)
+    }
+}
+
+impl From<&str> for UBytes {
+    fn from(s: &str) -> UBytes {
+        UBytes::from(s.as_bytes())
+    }
+}
+
+impl From<Ustr> for UBytes {
+    fn from(u: Ustr) -> UBytes {
+        UBytes::from(u.as_str().as_bytes())
+    }
+}
+
+impl From<&CStr> for UBytes {
+    fn from(s: &CStr) -> UBytes {
+        UBytes::from(s.to_bytes())
+    }
+}
+
+impl From<UBytes> for &'static [u8] {
+    fn from(b: UBytes) -> &'static [u8] {
+        b.as_bytes()
+    }
+}
+
+impl From<UBytes> for Vec<u8> {
+    fn from(b: UBytes) -> Vec<u8> {
+        b.as_bytes().to_vec()
+    }
+}
+
+impl Default for UBytes {
+    fn default() -> Self {
+        UBytes::from(b"")
+    }
+}
+
+impl Deref for UBytes {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        self.as_bytes()
+    }
+}
+
+impl fmt::Debug for UBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ub!(b\"{}\")", self.as_bytes().escape_ascii())
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, as for `Ustr`.
+impl Hash for UBytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+/// Create a new `UBytes` from the given bytes.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::ubytes;
+///
+/// let b1 = ubytes(b"\x00\x01\x02");
+/// let b2 = ubytes(&[0, 1, 2]);
+/// assert_eq!(b1, b2);
+/// assert_eq!(b1.len(), 3);
+/// ```
+#[inline]
+pub fn ubytes(bytes: &[u8]) -> UBytes {
+    UBytes::from(bytes)
+}
+
+/// Create a new `UBytes` from the given bytes but only if they have already
+/// been interned.
+#[inline]
+pub fn existing_ubytes(bytes: &[u8]) -> Option<UBytes> {
+    UBytes::from_existing(bytes)
+}
+
+lazy_static::lazy_static! {
+    static ref BYTES_CACHE: &'static Bins = new_global_cache(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{existing_ubytes, ubytes, UBytes};
+    use crate::{num_entries, ustr, UBytesMap};
+
+    #[test]
+    fn bytes() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        let all: Vec<u8> = (0..=255).collect();
+        let b = ubytes(&all);
+        assert_eq!(b, all);
+        assert_eq!(b.as_bytes().as_ptr(), ubytes(&all).as_ptr());
+        assert_eq!(existing_ubytes(&all), Some(b));
+        assert_eq!(existing_ubytes(b"not yet"), None);
+        assert!(b.as_cstr().is_none());
+        assert!(b.to_str().is_err());
+        assert_eq!(format!("{:?}", ubytes(b"a\"\xff")), r#"ub!(b"a\"\xff")"#);
+
+        let empty = UBytes::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.as_cstr().unwrap().to_bytes(), b"");
+
+        // Byte strings don't go in the string cache, and valid UTF-8 hashes
+        // the same either way.
+        assert_eq!(num_entries(), 0);
+        let hello = ubytes(b"hello");
+        assert_eq!(hello.to_str().unwrap(), ustr("hello"));
+        assert_eq!(hello.precomputed_hash(), ustr("hello").precomputed_hash());
+        assert_eq!(<UBytes as From<_>>::from(ustr("hello")), hello);
+        assert_eq!(num_entries(), 1);
+
+        let mut map = UBytesMap::default();
+        for i in 0..=255u8 {
+            map.insert(ubytes(&[i, 0, i]), i);
+        }
+        for i in 0..=255u8 {
+            assert_eq!(map[&ubytes(&[i, 0, i])], i);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use super::{InternError, UBytes, Ustr};
+use std::{
+    cmp::Ordering,
+    ffi::OsStr,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A handle representing a path in the global byte string cache.
+///
+/// Paths are interned as the bytes of their [`OsStr`], so, unlike going
+/// through a `Ustr`, paths that aren't valid UTF-8 are kept exactly as they
+/// are. A `UPath` derefs to a `&'static Path`, and [`join`](UPath::join),
+/// [`parent`](UPath::parent) and [`file_name`](UPath::file_name) give back
+/// interned handles too.
+///
+/// Equality is pointer equality, so two paths are only equal if they have
+/// exactly the same bytes: `a/b` and `a//b` are different `UPath`s even though
+/// they're equal as `Path`s. Ordering is by the bytes too, to match.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{upath, ustr, UPath};
+///
+/// let src = upath("project/src");
+/// let lib = src.join("lib.rs");
+/// assert_eq!(lib, upath("project/src/lib.rs"));
+/// assert_eq!(lib.parent(), Some(src));
+/// assert_eq!(lib.file_name(), Some(upath("lib.rs")));
+/// assert_eq!(lib.to_ustr(), Some(ustr("project/src/lib.rs")));
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct UPath {
+    bytes: UBytes,
+}
+
+impl Ord for UPath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bytes.cmp(&other.bytes)
+    }
+}
+
+impl PartialOrd for UPath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl UPath {
+    /// Create a new `UPath` from the given path.
+    ///
+    /// You can also use the [`upath`] function.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path can't be interned, either because we ran out of
+    /// memory or because one of the configured limits was reached. Use
+    /// [`UPath::try_from`] to handle that instead.
+    pub fn from(path: &Path) -> UPath {
+        UPath::try_from(path)
+            .unwrap_or_else(|e| panic!("failed to intern path: {}", e))
+    }
+
+    /// Create a new `UPath` from the given path, returning an error rather
+    /// than panicking if it can't be stored.
+    pub fn try_from(path: &Path) -> Result<UPath, InternError> {
+        UBytes::try_from(path.as_os_str().as_encoded_bytes())
+            .map(|bytes| UPath { bytes })
+    }
+
+    /// Get the `UPath` for the given path, but only if it has already been
+    /// interned. This never takes a lock.
+    pub fn from_existing(path: &Path) -> Option<UPath> {
+        UBytes::from_existing(path.as_os_str().as_encoded_bytes())
+            .map(|bytes| UPath { bytes })
+    }
+
+    /// Get the interned path.
+    pub fn as_path(&self) -> &'static Path {
+        Path::new(self.as_os_str())
+    }
+
+    /// Get the interned path as an `OsStr`.
+    pub fn as_os_str(&self) -> &'static OsStr {
+        // This is safe as the bytes were copied from the encoded bytes of an
+        // `OsStr`, or from a `str`, in this process.
+        unsafe { OsStr::from_encoded_bytes_unchecked(self.bytes.as_bytes()) }
+    }
+
+    /// Get the `Ustr` for this path if it's valid UTF-8.
+    ///
+    /// This interns the string in the global string cache.
+    pub fn to_ustr(&self) -> Option<Ustr> {
+        self.as_os_str().to_str().map(Ustr::from)
+    }
+
+    /// Intern the path made by joining `path` onto this one, as with
+    /// [`Path::join`].
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> UPath {
+        UPath::from(&self.as_path().join(path))
+    }
+
+    /// The interned parent of this path, as with [`Path::parent`].
+    ///
+    /// This is `None` for a root or an empty path, and the empty path for a
+    /// single relative component.
+    pub fn parent(&self) -> Option<UPath> {
+        self.as_path().parent().map(UPath::from)
+    }
+
+    /// The interned final component of this path, as with
+    /// [`Path::file_name`].
+    ///
+    /// This is `None` if the path ends in `..` or is a root.
+    pub fn file_name(&self) -> Option<UPath> {
+        self.as_path()
+            .file_name()
+            .map(|name| UPath::from(Path::new(name)))
+    }
+
+    /// Get the length in bytes of the encoded path.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns true if this is the empty path.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Get the precomputed hash for this path.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        self.bytes.precomputed_hash()
+    }
+}
+
+impl PartialEq<Path> for UPath {
+    fn eq(&self, other: &Path) -> bool {
+        self.as_path() == other
+    }
+}
+
+impl PartialEq<&Path> for UPath {
+    fn eq(&self, other: &&Path) -> bool {
+        self.as_path() == *other
+    }
+}
+
+impl PartialEq<PathBuf> for UPath {
+    fn eq(&self, other: &PathBuf) -> bool {
+        self.as_path() == other
+    }
+}
+
+impl PartialEq<UPath> for Path {
+    fn eq(&self, other: &UPath) -> bool {
+        self == other.as_path()
+    }
+}
+
+impl PartialEq<UPath> for &Path {
+    fn eq(&self, other: &UPath) -> bool {
+        *self == other.as_path()
+    }
+}
+
+impl PartialEq<UPath> for PathBuf {
+    fn eq(&self, other: &UPath) -> bool {
+        self == other.as_path()
+    }
+}
+
+impl AsRef<Path> for UPath {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<OsStr> for UPath {
+    fn as_ref(&self) -> &OsStr {
+        self.as_os_str()
+    }
+}
+
+impl From<&Path> for UPath {
+    fn from(path: &Path) -> UPath {
+        UPath::from(path)
+    }
+}
+
+impl From<PathBuf> for UPath {
+    fn from(path: PathBuf) -> UPath {
+        UPath::from(&path)
+    }
+}
+
+impl From<&OsStr> for UPath {
+    fn from(s: &OsStr) -> UPath {
+        UPath::from(Path::new(s))
+    }
+}
+
+impl From<&str> for UPath {
+    fn from(s: &str) -> UPath {
+        UPath::from(Path::new(s))
+    }
+}
+
+impl From<Ustr> for UPath {
+    fn from(u: Ustr) -> UPath {
+        UPath::from(Path::new(u.as_str()))
+    }
+}
+
+impl From<UPath> for &'static Path {
+    fn from(p: UPath) -> &'static Path {
+        p.as_path()
+    }
+}
+
+impl From<UPath> for PathBuf {
+    fn from(p: UPath) -> PathBuf {
+        p.as_path().to_path_buf()
+    }
+}
+
+impl Default for UPath {
+    fn default() -> Self {
+        UPath::from(Path::new(""))
+    }
+}
+
+impl Deref for UPath {
+    type Target = Path;
+    fn deref(&self) -> &Self::Target {
+        self.as_path()
+    }
+}
+
+impl fmt::Debug for UPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "up!({:?})", self.as_path())
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, as for `Ustr`.
+impl Hash for UPath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+/// Create a new `UPath` from the given path.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::Path;
+/// use ustr::upath;
+///
+/// let p = upath("assets/logo.png");
+/// assert_eq!(p, Path::new("assets/logo.png"));
+/// assert_eq!(p.extension().unwrap(), "png");
+/// ```
+#[inline]
+pub fn upath<P: AsRef<Path>>(path: P) -> UPath {
+    UPath::from(path.as_ref())
+}
+
+/// Create a new `UPath` from the given path but only if it has already been
+/// interned.
+#[inline]
+pub fn existing_upath<P: AsRef<Path>>(path: P) -> Option<UPath> {
+    UPath::from_existing(path.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{existing_upath, upath, UPath};
+    use crate::{ustr, UPathSet};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn paths() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        assert_eq!(existing_upath("a/b/c"), None);
+        let p = upath("a/b/c");
+        assert_eq!(existing_upath("a/b/c"), Some(p));
+        assert_eq!(p, Path::new("a/b/c"));
+        assert_eq!(p, PathBuf::from("a/b/c"));
+        assert_eq!(p.as_path().as_os_str().len(), p.len());
+
+        assert_eq!(p.parent(), Some(upath("a/b")));
+        assert_eq!(upath("a").parent(), Some(UPath::default()));
+        assert_eq!(UPath::default().parent(), None);
+        assert_eq!(p.file_name(), Some(upath("c")));
+        assert_eq!(upath("a/..").file_name(), None);
+        assert_eq!(upath("a/b").join("c"), p);
+
+        assert_eq!(p.to_ustr(), Some(ustr("a/b/c")));
+        assert_eq!(<UPath as From<_>>::from(ustr("a/b/c")), p);
+        assert_eq!(format!("{:?}", p), "up!(\"a/b/c\")");
+
+        // Paths that are equal as `Path`s but have different bytes are
+        // different `UPath`s.
+        assert_ne!(upath("a//b"), upath("a/b"));
+        assert_eq!(upath("a//b"), Path::new("a/b"));
+
+        let set: UPathSet = ["x", "y", "x"].into_iter().map(upath).collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        let raw = OsStr::from_bytes(b"dir/caf\xe9.txt");
+        let p = upath(raw);
+        assert_eq!(p.as_os_str().as_bytes(), b"dir/caf\xe9.txt");
+        assert_eq!(p.to_ustr(), None);
+        assert_eq!(
+            p.file_name().unwrap().as_os_str().as_bytes(),
+            b"caf\xe9.txt"
+        );
+        assert_eq!(p.parent(), Some(upath("dir")));
+        assert_eq!(crate::num_entries(), 0);
+    }
+}
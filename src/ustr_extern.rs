@@ -1,4 +1,4 @@
-use ustr::Ustr;
+use ustr::{UBytes, Ustr};
 
 #[no_mangle]
 pub extern "C" fn ustr(chars: *const std::os::raw::c_char) -> Ustr {
@@ -15,3 +15,19 @@ pub extern "C" fn ustr_len(u: Ustr) -> usize {
 pub extern "C" fn ustr_hash(u: Ustr) -> u64 {
     u.precomputed_hash()
 }
+
+#[no_mangle]
+pub extern "C" fn ubytes(bytes: *const u8, len: usize) -> UBytes {
+    // The pointer may be null if there are no bytes.
+    let bytes: &[u8] = if len == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(bytes, len) }
+    };
+    UBytes::from(bytes)
+}
+
+#[no_mangle]
+pub extern "C" fn ubytes_len(b: UBytes) -> usize {
+    b.len()
+}
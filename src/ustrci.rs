@@ -0,0 +1,355 @@
+use super::{InternError, Interned, Ustr};
+use std::{
+    borrow::{Borrow, Cow},
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+};
+
+/// A handle representing a string whose identity ignores case.
+///
+/// Two strings give the same `UstrCi` if they are equal after Unicode simple
+/// case folding, so `"Content-Type"` and `"content-type"` are the same
+/// `UstrCi`. The spelling the string had the first time it was interned is
+/// kept for display, and both it and the folded string are ordinary `Ustr`s.
+///
+/// Equality is pointer equality, and the hash is the precomputed hash of the
+/// folded string, so `UstrCi`s work with [`UstrCiMap`](crate::UstrCiMap) and
+/// [`UstrCiSet`](crate::UstrCiSet).
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ustr, UstrCi};
+///
+/// let a = UstrCi::from("Content-Type");
+/// let b = UstrCi::from("CONTENT-TYPE");
+/// assert_eq!(a, b);
+/// assert_eq!(b.as_str(), "Content-Type");
+/// assert_eq!(b.folded(), ustr("content-type"));
+/// assert_eq!(a, "content-type");
+/// assert_eq!(UstrCi::from("ΣΊΣΥΦΟΣ"), UstrCi::from("σίσυφος"));
+///
+/// let u: UstrCi = ustr("content-TYPE").into();
+/// assert_eq!(u, a);
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct UstrCi {
+    entry: Interned<CiEntry>,
+}
+
+// Only the folded string counts for equality, so the pool keeps whichever
+// spelling got there first.
+struct CiEntry {
+    folded: Ustr,
+    spelling: Ustr,
+}
+
+impl PartialEq for CiEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.folded == other.folded
+    }
+}
+
+impl Eq for CiEntry {}
+
+impl Hash for CiEntry {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.folded.hash(state);
+    }
+}
+
+// Lets us look an entry up by its folded string before we've interned the
+// spelling.
+impl Borrow<Ustr> for CiEntry {
+    fn borrow(&self) -> &Ustr {
+        &self.folded
+    }
+}
+
+impl UstrCi {
+    /// Intern the given string, ignoring case.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be interned, either because we ran out of
+    /// memory or because one of the configured limits was reached. Use
+    /// [`UstrCi::try_from`] to handle that instead.
+    pub fn from(string: &str) -> UstrCi {
+        UstrCi::try_from(string)
+            .unwrap_or_else(|e| panic!("failed to intern string: {}", e))
+    }
+
+    /// Intern the given string, ignoring case, returning an error rather than
+    /// panicking if it can't be stored.
+    ///
+    /// Only the first spelling of a string is interned as a `Ustr`; later
+    /// spellings that fold to the same string are just looked up.
+    pub fn try_from(string: &str) -> Result<UstrCi, InternError> {
+        let folded = match fold_case(string) {
+            Cow::Borrowed(_) => {
+                return UstrCi::try_from_ustr(Ustr::try_from(string)?)
+            }
+            Cow::Owned(s) => Ustr::try_from(&s)?,
+        };
+        if let Some(entry) = Interned::existing(&folded) {
+            return Ok(UstrCi { entry });
+        }
+        let spelling = Ustr::try_from(string)?;
+        Ok(UstrCi {
+            entry: Interned::new(CiEntry { folded, spelling }),
+        })
+    }
+
+    fn try_from_ustr(spelling: Ustr) -> Result<UstrCi, InternError> {
+        let folded = match fold_case(spelling.as_str()) {
+            Cow::Borrowed(_) => spelling,
+            Cow::Owned(s) => Ustr::try_from(&s)?,
+        };
+        Ok(UstrCi {
+            entry: Interned::new(CiEntry { folded, spelling }),
+        })
+    }
+
+    /// The spelling of the string the first time it was interned.
+    pub fn as_ustr(&self) -> Ustr {
+        self.entry.spelling
+    }
+
+    /// The spelling of the string the first time it was interned.
+    pub fn as_str(&self) -> &'static str {
+        self.entry.get().spelling.as_str()
+    }
+
+    /// The case folded string.
+    pub fn folded(&self) -> Ustr {
+        self.entry.folded
+    }
+
+    /// Get the precomputed hash of the folded string.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        self.entry.folded.precomputed_hash()
+    }
+}
+
+/// Fold the case of a string using Unicode simple case folding, so that two
+/// strings are equal after folding if they only differ in case.
+///
+/// Simple folding maps each character to a single character, unlike full
+/// case folding, so `ß` stays as it is rather than becoming `ss`.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(ustr::fold_case("Straße ΣΑΣ"), "straße σασ");
+/// ```
+pub fn fold_case(string: &str) -> Cow<'_, str> {
+    match string.char_indices().find(|&(_, c)| fold_char(c) != c) {
+        None => Cow::Borrowed(string),
+        Some((i, _)) => {
+            let mut folded = String::with_capacity(string.len());
+            folded.push_str(&string[..i]);
+            folded.extend(string[i..].chars().map(fold_char));
+            Cow::Owned(folded)
+        }
+    }
+}
+
+fn fold_char(c: char) -> char {
+    if c.is_ascii() {
+        return c.to_ascii_lowercase();
+    }
+    // Most characters fold to their lowercase form, except for these, whose
+    // lowercase is themselves or more than one character.
+    match c {
+        'µ' => 'μ',
+        'ſ' => 's',
+        '\u{345}' | '\u{1fbe}' => 'ι',
+        'ς' => 'σ',
+        'ϐ' => 'β',
+        'ϑ' => 'θ',
+        'ϕ' => 'φ',
+        'ϖ' => 'π',
+        'ϰ' => 'κ',
+        'ϱ' => 'ρ',
+        'ϵ' => 'ε',
+        '\u{1c80}' => 'в',
+        '\u{1c81}' => 'д',
+        '\u{1c82}' => 'о',
+        '\u{1c83}' => 'с',
+        '\u{1c84}' | '\u{1c85}' => 'т',
+        '\u{1c86}' => 'ъ',
+        '\u{1c87}' => 'ѣ',
+        '\u{1c88}' => '\u{a64b}',
+        'ẛ' => 'ṡ',
+        // Cherokee folds to uppercase, for compatibility with its earlier
+        // versions, which only had uppercase.
+        '\u{13a0}'..='\u{13f5}' => c,
+        '\u{13f8}'..='\u{13fd}' => char::from_u32(c as u32 - 8).unwrap_or(c),
+        '\u{ab70}'..='\u{abbf}' => {
+            char::from_u32(c as u32 - 0xab70 + 0x13a0).unwrap_or(c)
+        }
+        _ => {
+            let mut lower = c.to_lowercase();
+            match (lower.next(), lower.next()) {
+                (Some(l), None) => l,
+                _ => c,
+            }
+        }
+    }
+}
+
+impl PartialEq<str> for UstrCi {
+    fn eq(&self, other: &str) -> bool {
+        self.folded().as_str() == fold_case(other)
+    }
+}
+
+impl PartialEq<&str> for UstrCi {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq<Ustr> for UstrCi {
+    fn eq(&self, other: &Ustr) -> bool {
+        self == other.as_str()
+    }
+}
+
+/// Defer to the folded strings for ordering.
+impl Ord for UstrCi {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.folded().cmp(&other.folded())
+    }
+}
+
+impl PartialOrd for UstrCi {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, as for `Ustr`.
+impl Hash for UstrCi {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+impl From<&str> for UstrCi {
+    fn from(s: &str) -> UstrCi {
+        UstrCi::from(s)
+    }
+}
+
+impl From<Ustr> for UstrCi {
+    fn from(u: Ustr) -> UstrCi {
+        UstrCi::try_from_ustr(u)
+            .unwrap_or_else(|e| panic!("failed to intern string: {}", e))
+    }
+}
+
+impl From<UstrCi> for Ustr {
+    fn from(u: UstrCi) -> Ustr {
+        u.as_ustr()
+    }
+}
+
+impl Deref for UstrCi {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for UstrCi {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for UstrCi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for UstrCi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ci!({:?})", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold_case, fold_char, UstrCi};
+    use crate::{existing_ustr, ustr, Ustr, UstrCiSet};
+
+    #[test]
+    fn case_insensitive() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        let a = UstrCi::from("X-Forwarded-For");
+        assert_eq!(a, UstrCi::from("x-forwarded-for"));
+        assert_eq!(a, UstrCi::from("X-FORWARDED-FOR"));
+        assert_ne!(a, UstrCi::from("X-Forwarded-Host"));
+        assert_eq!(a.as_str(), "X-Forwarded-For");
+        // Later spellings aren't interned.
+        assert_eq!(existing_ustr("X-FORWARDED-FOR"), None);
+        assert_eq!(
+            <Ustr as From<_>>::from(UstrCi::from("x-forwarded-FOR")),
+            a.as_ustr()
+        );
+        assert_eq!(a, ustr("X-FORWARDED-for"));
+        assert_eq!(
+            a.precomputed_hash(),
+            ustr("x-forwarded-for").precomputed_hash()
+        );
+        assert_eq!(
+            format!("{} {:?}", a, a),
+            r#"X-Forwarded-For ci!("X-Forwarded-For")"#
+        );
+
+        // Simple folding keeps the length in characters.
+        assert_ne!(UstrCi::from("STRASSE"), UstrCi::from("straße"));
+        assert_eq!(UstrCi::from("STRAẞE"), UstrCi::from("straße"));
+        assert_eq!(UstrCi::from("ΣΑΣ"), UstrCi::from("σας"));
+        assert_eq!(UstrCi::from("Ꭰ"), UstrCi::from("ꭰ"));
+        assert_eq!(fold_case("already folded"), "already folded");
+        assert!(matches!(fold_case("abc"), std::borrow::Cow::Borrowed(_)));
+        assert_eq!(fold_case("ǅ İ K"), "ǆ İ k");
+
+        assert!(UstrCi::from("apple") < UstrCi::from("Banana"));
+        let set: UstrCiSet = ["Accept", "ACCEPT", "accept", "Host"]
+            .into_iter()
+            .map(UstrCi::from)
+            .collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    // Far too slow for miri.
+    #[cfg_attr(miri, ignore)]
+    fn matches_case_folding_tables() {
+        // caseless does full case folding, which agrees with simple folding
+        // for the characters that fold to a single character. Its tables are
+        // older than std's, so only check the characters it knows how to
+        // fold.
+        for c in (0..=0x10ffff).filter_map(char::from_u32) {
+            let s = c.to_string();
+            let full: String = caseless::default_case_fold_str(&s);
+            let mut chars = full.chars();
+            if full == s {
+                continue;
+            }
+            if let (Some(f), None) = (chars.next(), chars.next()) {
+                assert_eq!(fold_char(c), f, "{:?} U+{:04X}", c, c as u32);
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use super::{new_global_cache, Bins, InternError, StringCacheEntry, Ustr};
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    mem::size_of,
+    ops::Deref,
+    ptr::NonNull,
+    slice,
+};
+
+/// A handle representing an interned list of `Ustr`s, such as the parts of a
+/// qualified name.
+///
+/// Each distinct list is stored once, as an array of `Ustr`s in the same kind
+/// of leaky arena as the strings themselves, so a `UstrList` is a single
+/// pointer that compares by pointer and carries a hash computed from the
+/// hashes of its elements. It derefs to a `&'static [Ustr]`.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ustr, UstrList};
+///
+/// let std = UstrList::from_strs(&["std", "collections"]);
+/// let map = std.push(ustr("HashMap"));
+/// assert_eq!(map, UstrList::from_strs(&["std", "collections", "HashMap"]));
+/// assert_eq!(map.join("::"), "std::collections::HashMap");
+/// assert_eq!(map.pop(), Some((std, ustr("HashMap"))));
+/// assert_eq!(map[1], "collections");
+/// assert_eq!(map.len(), 3);
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct UstrList {
+    // Points to the array of `Ustr`s in `LIST_CACHE`.
+    elems_ptr: NonNull<u8>,
+}
+
+impl UstrList {
+    /// Intern the given list.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the list can't be interned, either because we ran out of
+    /// memory or because one of the configured limits was reached. Use
+    /// [`UstrList::try_new`] to handle that instead.
+    pub fn new(elems: &[Ustr]) -> UstrList {
+        UstrList::try_new(elems)
+            .unwrap_or_else(|e| panic!("failed to intern list: {}", e))
+    }
+
+    /// Intern the given list, returning an error rather than panicking if it
+    /// can't be stored.
+    pub fn try_new(elems: &[Ustr]) -> Result<UstrList, InternError> {
+        // This is safe as a `Ustr` is just a pointer.
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                elems.as_ptr() as *const u8,
+                std::mem::size_of_val(elems),
+            )
+        };
+        // Hash the hashes of the elements rather than their addresses, so
+        // that a list's hash is the same in every process that uses the same
+        // hash function.
+        let key: Vec<u8> = elems
+            .iter()
+            .flat_map(|u| u.precomputed_hash().to_le_bytes())
+            .collect();
+        let hash = LIST_CACHE.hash_bytes(&key);
+        let ptr = LIST_CACHE.insert_bytes(bytes, hash)?;
+        Ok(UstrList {
+            // SAFETY: insert does not give back a null pointer
+            elems_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        })
+    }
+
+    /// Intern each of the given strings and then the list of them.
+    pub fn from_strs(strs: &[&str]) -> UstrList {
+        let elems: Vec<Ustr> = strs.iter().map(|s| Ustr::from(s)).collect();
+        UstrList::new(&elems)
+    }
+
+    /// Get the list as a slice.
+    pub fn as_slice(&self) -> &'static [Ustr] {
+        let entry =
+            unsafe { StringCacheEntry::from_char_ptr(self.elems_ptr.as_ptr()) };
+        // This is safe as the bytes were copied from a slice of `Ustr`s, and
+        // entries are aligned to at least the alignment of a pointer.
+        unsafe {
+            slice::from_raw_parts(
+                self.elems_ptr.as_ptr() as *const Ustr,
+                entry.len / size_of::<Ustr>(),
+            )
+        }
+    }
+
+    /// Intern the list with `elem` added to the end of this one.
+    pub fn push(&self, elem: Ustr) -> UstrList {
+        let mut elems = Vec::with_capacity(self.len() + 1);
+        elems.extend_from_slice(self.as_slice());
+        elems.push(elem);
+        UstrList::new(&elems)
+    }
+
+    /// Split off the last element, giving back the interned list of the rest
+    /// and the last element, or `None` if the list is empty.
+    pub fn pop(&self) -> Option<(UstrList, Ustr)> {
+        let (last, rest) = self.as_slice().split_last()?;
+        Some((UstrList::new(rest), *last))
+    }
+
+    /// Join the elements with `sep` between them and intern the result.
+    pub fn join(&self, sep: &str) -> Ustr {
+        let strs: Vec<&str> = self.iter().map(|u| u.as_str()).collect();
+        Ustr::from(strs.join(sep).as_str())
+    }
+
+    /// Get the precomputed hash for this list.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        unsafe { StringCacheEntry::from_char_ptr(self.elems_ptr.as_ptr()) }.hash
+    }
+}
+
+// We're safe to impl these for the same reasons as for `Ustr`.
+unsafe impl Send for UstrList {}
+unsafe impl Sync for UstrList {}
+
+/// Defer to the elements for ordering.
+impl Ord for UstrList {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+impl PartialOrd for UstrList {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq<[Ustr]> for UstrList {
+    fn eq(&self, other: &[Ustr]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+impl PartialEq<&[Ustr]> for UstrList {
+    fn eq(&self, other: &&[Ustr]) -> bool {
+        self.as_slice() == *other
+    }
+}
+
+impl From<&[Ustr]> for UstrList {
+    fn from(elems: &[Ustr]) -> UstrList {
+        UstrList::new(elems)
+    }
+}
+
+impl From<Vec<Ustr>> for UstrList {
+    fn from(elems: Vec<Ustr>) -> UstrList {
+        UstrList::new(&elems)
+    }
+}
+
+impl FromIterator<Ustr> for UstrList {
+    fn from_iter<I: IntoIterator<Item = Ustr>>(iter: I) -> UstrList {
+        UstrList::new(&iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl Default for UstrList {
+    fn default() -> Self {
+        UstrList::new(&[])
+    }
+}
+
+impl Deref for UstrList {
+    type Target = [Ustr];
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[Ustr]> for UstrList {
+    fn as_ref(&self) -> &[Ustr] {
+        self.as_slice()
+    }
+}
+
+impl fmt::Debug for UstrList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.iter().map(|u| u.as_str()))
+            .finish()
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, as for `Ustr`.
+impl Hash for UstrList {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref LIST_CACHE: &'static Bins = new_global_cache(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UstrList;
+    use crate::{ustr, Ustr, UstrListSet};
+
+    #[test]
+    fn lists() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        let empty = UstrList::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.pop(), None);
+        assert_eq!(empty.join("."), "");
+
+        let abc = UstrList::from_strs(&["a", "b", "c"]);
+        assert_eq!(abc, [ustr("a"), ustr("b"), ustr("c")][..]);
+        assert_eq!(abc, UstrList::from_strs(&["a", "b"]).push(ustr("c")));
+        assert_ne!(abc, UstrList::from_strs(&["c", "b", "a"]));
+        assert_eq!(abc.join("."), ustr("a.b.c"));
+        assert_eq!(format!("{:?}", abc), r#"["a", "b", "c"]"#);
+
+        let mut list = abc;
+        let mut popped = Vec::new();
+        while let Some((rest, last)) = list.pop() {
+            popped.push(last);
+            list = rest;
+        }
+        assert_eq!(list, empty);
+        assert_eq!(popped, ["c", "b", "a"]);
+
+        assert!(UstrList::from_strs(&["a", "b"]) < abc);
+        assert!(abc < UstrList::from_strs(&["b"]));
+
+        let set: UstrListSet = ["x", "y", "x"]
+            .into_iter()
+            .map(|s| s.split('.').map(Ustr::from).collect())
+            .collect();
+        assert_eq!(set.len(), 2);
+    }
+}
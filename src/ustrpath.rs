@@ -0,0 +1,444 @@
+use super::{new_global_cache, Bins, InternError, StringCacheEntry, Ustr};
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    mem::size_of,
+    ptr::NonNull,
+};
+
+/// A handle representing a `/`-separated path as a chain of interned
+/// components.
+///
+/// Each `UstrPath` is a pair of its parent `UstrPath` and its last component,
+/// a [`Ustr`], and each distinct pair is only stored once. Paths that share a
+/// directory share all of the storage for it, so a large set of paths with
+/// long common prefixes takes far less memory than interning each of them in
+/// full. [`parent()`](UstrPath::parent) is a single load, and
+/// [`starts_with()`](UstrPath::starts_with) walks up from the longer path to
+/// the depth of the shorter one and compares pointers, without looking at any
+/// strings. The full path is only built when you ask for it, with
+/// `to_string()` or [`to_ustr()`](UstrPath::to_ustr).
+///
+/// Paths are split on every `/`, so `a//b` has an empty component between
+/// `a` and `b`, and `/a` has an empty first component. This means that
+/// turning a `UstrPath` back into a string always gives exactly the string it
+/// was made from.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ustr, UstrPath};
+///
+/// let src = UstrPath::new("project/src");
+/// let lib = UstrPath::new("project/src/lib.rs");
+/// assert_eq!(lib.parent(), Some(src));
+/// assert_eq!(lib.file_name(), ustr("lib.rs"));
+/// assert_eq!(lib.depth(), 3);
+/// assert!(lib.starts_with(src));
+/// assert_eq!(src.join("lib.rs"), lib);
+/// assert_eq!(lib.to_string(), "project/src/lib.rs");
+///
+/// let names: Vec<_> = lib.components().collect();
+/// assert_eq!(names, ["project", "src", "lib.rs"]);
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct UstrPath {
+    // Points to the node in `PATH_CACHE`, which is a `Node` in native byte
+    // order.
+    node_ptr: NonNull<u8>,
+}
+
+// The bytes stored in the cache for each path.
+#[repr(C)]
+struct Node {
+    // The chars of the parent's node, or null.
+    parent: *const u8,
+    // The chars of the last component.
+    component: *const u8,
+    // Number of components.
+    depth: usize,
+}
+
+const NODE_LEN: usize = size_of::<Node>();
+
+impl UstrPath {
+    /// Intern the given path.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path can't be interned, either because we ran out of
+    /// memory or because one of the configured limits was reached. Use
+    /// [`UstrPath::try_new`] to handle that instead.
+    pub fn new(path: &str) -> UstrPath {
+        UstrPath::try_new(path)
+            .unwrap_or_else(|e| panic!("failed to intern path: {}", e))
+    }
+
+    /// Intern the given path, returning an error rather than panicking if it
+    /// can't be stored.
+    pub fn try_new(path: &str) -> Result<UstrPath, InternError> {
+        let mut components = path.split('/');
+        // `split` always gives at least one piece.
+        let first = Ustr::try_from(components.next().unwrap_or(""))?;
+        let mut node = UstrPath::try_node(None, first)?;
+        for component in components {
+            node = UstrPath::try_node(Some(node), Ustr::try_from(component)?)?;
+        }
+        Ok(node)
+    }
+
+    /// Get the path with the given parent and last component.
+    ///
+    /// The component is used as it is, even if it has a `/` in it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path can't be interned, like [`UstrPath::new`].
+    pub fn from_parts(parent: Option<UstrPath>, component: Ustr) -> UstrPath {
+        UstrPath::try_node(parent, component)
+            .unwrap_or_else(|e| panic!("failed to intern path: {}", e))
+    }
+
+    fn try_node(
+        parent: Option<UstrPath>,
+        component: Ustr,
+    ) -> Result<UstrPath, InternError> {
+        let node = Node {
+            parent: parent.map_or(std::ptr::null(), |p| p.node_ptr.as_ptr()),
+            component: component.char_ptr.as_ptr(),
+            depth: parent.map_or(0, |p| p.depth()) + 1,
+        };
+        // This is safe as `Node` is plain old data with no padding.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &node as *const Node as *const u8,
+                NODE_LEN,
+            )
+        };
+        // Hash the hashes of the parts rather than their addresses, so that
+        // a path's hash is the same in every process that uses the same hash
+        // function.
+        let mut key = [0u8; 16];
+        key[..8].copy_from_slice(
+            &parent.map_or(0, |p| p.precomputed_hash()).to_le_bytes(),
+        );
+        key[8..].copy_from_slice(&component.precomputed_hash().to_le_bytes());
+        let hash = PATH_CACHE.hash_bytes(&key);
+        let ptr = PATH_CACHE.insert_bytes(bytes, hash)?;
+        Ok(UstrPath {
+            // SAFETY: insert does not give back a null pointer
+            node_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        })
+    }
+
+    #[inline]
+    fn node(&self) -> &'static Node {
+        // This is safe as the node was copied from a `Node`, and entries are
+        // aligned to at least the alignment of a `usize`.
+        unsafe { &*(self.node_ptr.as_ptr() as *const Node) }
+    }
+
+    /// The path without its last component, or `None` if it only has one.
+    #[inline]
+    pub fn parent(&self) -> Option<UstrPath> {
+        NonNull::new(self.node().parent as *mut u8)
+            .map(|node_ptr| UstrPath { node_ptr })
+    }
+
+    /// The last component of the path.
+    #[inline]
+    pub fn file_name(&self) -> Ustr {
+        Ustr {
+            // This is safe as the pointer came from a `Ustr`.
+            char_ptr: unsafe {
+                NonNull::new_unchecked(self.node().component as *mut u8)
+            },
+        }
+    }
+
+    /// The number of components in the path, which is always at least 1.
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.node().depth
+    }
+
+    /// Intern the path made by adding the components of `path` to the end
+    /// of this one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path can't be interned, like [`UstrPath::new`].
+    pub fn join(&self, path: &str) -> UstrPath {
+        path.split('/').fold(*self, |node, component| {
+            UstrPath::from_parts(Some(node), Ustr::from(component))
+        })
+    }
+
+    /// Returns true if `prefix` is this path or one of its ancestors.
+    pub fn starts_with(&self, prefix: UstrPath) -> bool {
+        prefix.depth() <= self.depth()
+            && self.ancestor_at(prefix.depth()) == prefix
+    }
+
+    // Get the ancestor with the given depth, which must be at least 1 and no
+    // more than the depth of this path.
+    fn ancestor_at(&self, depth: usize) -> UstrPath {
+        debug_assert!(depth >= 1 && depth <= self.depth());
+        let mut node = *self;
+        while node.depth() > depth {
+            // Every path deeper than 1 has a parent.
+            node = node.parent().expect("path has no parent");
+        }
+        node
+    }
+
+    /// Iterate over this path and then each of its ancestors in turn.
+    pub fn ancestors(&self) -> UstrPathAncestors {
+        UstrPathAncestors { next: Some(*self) }
+    }
+
+    /// Iterate over the components of the path from the first to the last.
+    pub fn components(&self) -> UstrPathComponents {
+        UstrPathComponents {
+            back: *self,
+            front: 1,
+            len: self.depth(),
+        }
+    }
+
+    /// Intern the full path as a `Ustr`.
+    pub fn to_ustr(&self) -> Ustr {
+        Ustr::from(self.to_string().as_str())
+    }
+
+    /// Get the precomputed hash for this path.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        // This is safe as the node is in the cache.
+        unsafe { StringCacheEntry::from_char_ptr(self.node_ptr.as_ptr()) }.hash
+    }
+}
+
+// We're safe to impl these for the same reasons as for `Ustr`.
+unsafe impl Send for UstrPath {}
+unsafe impl Sync for UstrPath {}
+
+/// Defer to the components for ordering, so paths sort the same way as the
+/// strings they are made from would if `/` sorted before everything else.
+impl Ord for UstrPath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Start from the same depth, and if one path is then the other, the
+        // shorter one comes first.
+        let depth = self.depth().min(other.depth());
+        let mut a = self.ancestor_at(depth);
+        let mut b = other.ancestor_at(depth);
+        if a == b {
+            return self.depth().cmp(&other.depth());
+        }
+        // Otherwise walk up to the first components that differ, which are
+        // the ones just below where the paths meet. Paths with the same
+        // parent and last component are the same path, so they have to have
+        // different last components there.
+        while a.parent() != b.parent() {
+            // The paths are different, so they're at least 2 deep here.
+            a = a.parent().expect("path has no parent");
+            b = b.parent().expect("path has no parent");
+        }
+        a.file_name().as_str().cmp(b.file_name().as_str())
+    }
+}
+
+impl PartialOrd for UstrPath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<&str> for UstrPath {
+    fn from(path: &str) -> UstrPath {
+        UstrPath::new(path)
+    }
+}
+
+impl From<Ustr> for UstrPath {
+    fn from(path: Ustr) -> UstrPath {
+        UstrPath::new(path.as_str())
+    }
+}
+
+impl fmt::Display for UstrPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(parent) = self.parent() {
+            write!(f, "{}/", parent)?;
+        }
+        f.write_str(self.file_name().as_str())
+    }
+}
+
+impl fmt::Debug for UstrPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UstrPath({:?})", self.to_string())
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, as for `Ustr`.
+impl Hash for UstrPath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+/// Iterator over a path and its ancestors, returned by
+/// [`UstrPath::ancestors`].
+#[derive(Clone)]
+pub struct UstrPathAncestors {
+    next: Option<UstrPath>,
+}
+
+impl Iterator for UstrPathAncestors {
+    type Item = UstrPath;
+
+    fn next(&mut self) -> Option<UstrPath> {
+        let node = self.next?;
+        self.next = node.parent();
+        Some(node)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.next.map_or(0, |p| p.depth());
+        (n, Some(n))
+    }
+}
+
+impl ExactSizeIterator for UstrPathAncestors {}
+
+/// Iterator over the components of a path, returned by
+/// [`UstrPath::components`].
+///
+/// Paths only link to their parents, so iterating from the back is a single
+/// load per component, while each step from the front walks up from the back.
+#[derive(Clone)]
+pub struct UstrPathComponents {
+    // The path whose last component is the last one left.
+    back: UstrPath,
+    // Depth of the first component left.
+    front: usize,
+    // Number of components left.
+    len: usize,
+}
+
+impl Iterator for UstrPathComponents {
+    type Item = Ustr;
+
+    fn next(&mut self) -> Option<Ustr> {
+        if self.len == 0 {
+            return None;
+        }
+        let component = self.back.ancestor_at(self.front).file_name();
+        self.front += 1;
+        self.len -= 1;
+        Some(component)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl DoubleEndedIterator for UstrPathComponents {
+    fn next_back(&mut self) -> Option<Ustr> {
+        if self.len == 0 {
+            return None;
+        }
+        let component = self.back.file_name();
+        self.len -= 1;
+        if self.len > 0 {
+            self.back = self.back.parent().expect("path has no parent");
+        }
+        Some(component)
+    }
+}
+
+impl ExactSizeIterator for UstrPathComponents {}
+
+lazy_static::lazy_static! {
+    static ref PATH_CACHE: &'static Bins = new_global_cache(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UstrPath;
+    use crate::{ustr, UstrPathSet};
+
+    #[test]
+    fn paths() {
+        let _t = crate::TEST_LOCK.lock();
+        unsafe { crate::_clear_cache() };
+
+        for s in ["", "a", "/", "/a/b", "a//b/", "a/b/c"] {
+            let p = UstrPath::new(s);
+            assert_eq!(p.to_string(), s);
+            assert_eq!(p.to_ustr(), ustr(s));
+            assert_eq!(p, UstrPath::new(s));
+            assert_eq!(p.depth(), s.split('/').count());
+            assert_eq!(p.components().len(), p.depth());
+            assert_eq!(p.ancestors().count(), p.depth());
+        }
+
+        let abc = UstrPath::new("a/b/c");
+        let ab = UstrPath::new("a/b");
+        assert_eq!(abc.parent(), Some(ab));
+        assert_eq!(UstrPath::new("a").parent(), None);
+        assert_eq!(abc.file_name(), "c");
+        assert_eq!(
+            UstrPath::from_parts(Some(ab), ustr("c")),
+            UstrPath::new("a/b/c")
+        );
+        assert_eq!(ab.join("c/d").parent(), Some(abc));
+        assert_eq!(abc.components().rev().collect::<Vec<_>>(), ["c", "b", "a"]);
+
+        assert!(abc.starts_with(ab));
+        assert!(abc.starts_with(abc));
+        assert!(!ab.starts_with(abc));
+        assert!(!abc.starts_with(UstrPath::new("a/c")));
+        assert!(!abc.starts_with(UstrPath::new("b")));
+
+        assert!(UstrPath::new("a/b") < UstrPath::new("a/b/c"));
+        assert!(UstrPath::new("a/b/c") < UstrPath::new("a/c"));
+        assert!(UstrPath::new("a/b") < UstrPath::new("a-b"));
+        assert_eq!(format!("{:?}", abc), r#"UstrPath("a/b/c")"#);
+
+        // Ordering matches comparing the components as strings.
+        let paths = ["", "a", "b", "a/", "a/b", "a/b/c", "a/c", "b/a", "/a"];
+        for x in paths.map(UstrPath::new) {
+            for y in paths.map(UstrPath::new) {
+                let by_components = x
+                    .components()
+                    .map(|c| c.as_str())
+                    .cmp(y.components().map(|c| c.as_str()));
+                assert_eq!(x.cmp(&y), by_components, "{} {}", x, y);
+            }
+        }
+
+        let mut components = UstrPath::new("a/b/c/d").components();
+        assert_eq!(components.next(), Some(ustr("a")));
+        assert_eq!(components.next_back(), Some(ustr("d")));
+        assert_eq!(components.len(), 2);
+        assert_eq!(components.next(), Some(ustr("b")));
+        assert_eq!(components.next_back(), Some(ustr("c")));
+        assert_eq!(components.next(), None);
+        assert_eq!(components.next_back(), None);
+
+        // Shared prefixes are stored once.
+        let set: UstrPathSet = ["x/y/1", "x/y/2", "x/y/3", "x/y/1"]
+            .into_iter()
+            .map(UstrPath::new)
+            .collect();
+        assert_eq!(set.len(), 3);
+        let nodes = super::PATH_CACHE.num_entries();
+        UstrPath::new("x/y/4");
+        assert_eq!(super::PATH_CACHE.num_entries(), nodes + 1);
+    }
+}